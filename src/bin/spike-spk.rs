@@ -1,9 +1,13 @@
 use std::{
     ffi::OsStr,
+    fmt::Write as _,
+    io::{IsTerminal as _, Write as _},
     path::{Path, PathBuf},
+    process::ExitCode,
 };
 
 use clap::Parser as _;
+use spike_spk::spk::ParseMode;
 
 /// Extract or verify a Stern Pinball software update package
 ///
@@ -15,25 +19,81 @@ use clap::Parser as _;
 struct Args {
     #[command(subcommand)]
     command: Commands,
+
+    /// Skip chunks of an unrecognized type instead of failing, reporting each
+    /// one as a warning.
+    #[arg(long, global = true)]
+    lenient: bool,
 }
 
+/// Exit status when one or more files failed MD5 or HMAC verification.
+const EXIT_INTEGRITY_FAILURE: u8 = 3;
+/// Exit status when the update could not be parsed.
+const EXIT_PARSE_FAILURE: u8 = 4;
+/// Exit status when the update could not be read.
+const EXIT_IO_FAILURE: u8 = 5;
+
 trait Command {
-    fn run(&self) -> anyhow::Result<()>;
+    fn run(&self, mode: ParseMode) -> anyhow::Result<ExitCode>;
+}
+
+/// Open the SPK file at `path`, reporting any chunks that were skipped.
+fn open(path: &Path, mode: ParseMode) -> anyhow::Result<spike_spk::SPKFile<'static>> {
+    let file = spike_spk::SPKFile::open_with_mode(path, mode)?;
+    for chunk in &file.unknown_chunks {
+        eprintln!("Warning: {chunk}");
+    }
+    Ok(file)
 }
 
 #[derive(Debug, clap::Subcommand)]
 enum Commands {
     /// Verify the contents of a SPK file.
+    ///
+    /// Exits with status 3 if any file fails verification, 4 if the update
+    /// cannot be parsed, and 5 if it cannot be read.
     Verify(VerifyCommand),
     /// Extract the contents of a SPK file.
     Extract(ExtractCommand),
+    /// List the contents of a SPK file without reading file data.
+    List(ListCommand),
+    /// Summarize the format and packages of a SPK file.
+    Info(InfoCommand),
+    /// Compare the packages and files of two SPK files.
+    Diff(DiffCommand),
+    /// Apply a sequence of updates on top of an existing file tree.
+    Overlay(OverlayCommand),
+    /// Install a SPK file into a file tree the way a machine's updater does.
+    ///
+    /// Files are staged and the files they replace are backed up, so the
+    /// install can be undone with `rollback`.
+    Install(InstallCommand),
+    /// Undo the most recent install into a file tree.
+    Rollback(RollbackCommand),
+    /// Verify a single file within a SPK file and write its contents to stdout.
+    ///
+    /// Exits with status 3 if the file fails verification.
+    Cat(CatCommand),
+    /// Export the contents of a SPK file as a tar archive.
+    Export(ExportCommand),
+    /// Print every chunk within a SPK file with its offset and raw fields.
+    Inspect(InspectCommand),
 }
 
 impl Command for Commands {
-    fn run(&self) -> anyhow::Result<()> {
+    fn run(&self, mode: ParseMode) -> anyhow::Result<ExitCode> {
         match self {
-            Commands::Verify(cmd) => cmd.run(),
-            Commands::Extract(cmd) => cmd.run(),
+            Commands::Verify(cmd) => cmd.run(mode),
+            Commands::Extract(cmd) => cmd.run(mode),
+            Commands::List(cmd) => cmd.run(mode),
+            Commands::Info(cmd) => cmd.run(mode),
+            Commands::Diff(cmd) => cmd.run(mode),
+            Commands::Overlay(cmd) => cmd.run(mode),
+            Commands::Install(cmd) => cmd.run(mode),
+            Commands::Rollback(cmd) => cmd.run(mode),
+            Commands::Cat(cmd) => cmd.run(mode),
+            Commands::Export(cmd) => cmd.run(mode),
+            Commands::Inspect(cmd) => cmd.run(mode),
         }
     }
 }
@@ -46,12 +106,804 @@ struct VerifyCommand {
     /// containing the split update files (.spk.OOX.00{1,2,...}),
     /// or the path to the first of the spilt update files (.spk.OON.000).
     path: PathBuf,
+
+    /// The format to write the results in.
+    #[arg(long, value_enum, default_value_t)]
+    format: OutputFormat,
 }
 
 impl Command for VerifyCommand {
-    fn run(&self) -> anyhow::Result<()> {
-        let mut file = spike_spk::SPKFile::open(&self.path)?;
-        spike_spk::verify::verify(&mut file)
+    fn run(&self, mode: ParseMode) -> anyhow::Result<ExitCode> {
+        let file = open(&self.path, mode)?;
+        let report = spike_spk::verify::verify(&file);
+
+        if let Some(format) = self.format.records() {
+            write_verification_records(format, &report)?;
+            print_verification_summary(&mut std::io::stderr(), &report)?;
+        } else {
+            print_verification_report(&report);
+            println!("\n");
+            print_verification_summary(&mut std::io::stdout(), &report)?;
+        }
+
+        Ok(if report.failures().any(|(_, file)| read_failed(file)) {
+            ExitCode::from(EXIT_IO_FAILURE)
+        } else if report.passed() {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::from(EXIT_INTEGRITY_FAILURE)
+        })
+    }
+}
+
+/// Whether `file` could not be read, as opposed to failing verification.
+fn read_failed(file: &spike_spk::verify::FileReport) -> bool {
+    file.result
+        .as_ref()
+        .is_err_and(spike_spk::verify::FileError::is_io)
+}
+
+fn write_verification_records(
+    format: RecordFormat,
+    report: &spike_spk::verify::VerificationReport,
+) -> anyhow::Result<()> {
+    let records = report.packages.iter().flat_map(|package| {
+        package.files.iter().map(move |file| {
+            let mut record =
+                FileRecord::new(&package.name, package.version, package.type_, &file.file);
+            record.status = Some(if file.passed() {
+                "passed"
+            } else if read_failed(file) {
+                "error"
+            } else {
+                "failed"
+            });
+            record.md5_matches = Some(file.md5_matches());
+            record.hmac_matches = Some(file.hmac_matches());
+            record.error = file.result.as_ref().err().map(ToString::to_string);
+            record
+        })
+    });
+    write_records(format, records)
+}
+
+#[derive(Debug, clap::Args)]
+struct ListCommand {
+    /// The path to the SPK file to list.
+    ///
+    /// The path can be the path to a single .spk file, the path to a directory
+    /// containing the split update files (.spk.OOX.00{1,2,...}),
+    /// or the path to the first of the spilt update files (.spk.OON.000).
+    path: PathBuf,
+
+    /// Include the offset of each file's data within the SPK file.
+    #[arg(long)]
+    offsets: bool,
+
+    /// The format to write the listing in.
+    #[arg(long, value_enum, default_value_t)]
+    format: OutputFormat,
+}
+
+impl Command for ListCommand {
+    fn run(&self, mode: ParseMode) -> anyhow::Result<ExitCode> {
+        let file = open(&self.path, mode)?;
+
+        if let Some(format) = self.format.records() {
+            let records = file.packages.iter().flat_map(|package| {
+                package.files.iter().map(|file| {
+                    let mut record =
+                        FileRecord::new(&package.name, package.version, package.type_, file);
+                    record.offset = self.offsets.then(|| file.data_offset());
+                    record
+                })
+            });
+            write_records(format, records)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+
+        for (i, package) in file.packages.iter().enumerate() {
+            if i > 0 {
+                println!("\n");
+            }
+
+            println!("Package: {}", package.name);
+            println!(
+                "Version: {}.{}.{}",
+                package.version.0, package.version.1, package.version.2
+            );
+
+            let mut files = package.files.iter().collect::<Vec<_>>();
+            files.sort_by(|a, b| a.name.cmp(&b.name));
+            for file in files {
+                print!("{} {:11}  ", mode_string(file.mode), file.size);
+                if self.offsets {
+                    print!("{:#012x}  ", file.data_offset());
+                }
+                println!("{}{}", package.type_.path_prefix(), file.name);
+            }
+        }
+
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct InfoCommand {
+    /// The path to the SPK file to summarize.
+    ///
+    /// The path can be the path to a single .spk file, the path to a directory
+    /// containing the split update files (.spk.OOX.00{1,2,...}),
+    /// or the path to the first of the spilt update files (.spk.OON.000).
+    path: PathBuf,
+
+    /// Include the raw values of fields whose meaning is unknown.
+    #[arg(long)]
+    raw: bool,
+}
+
+impl Command for InfoCommand {
+    fn run(&self, mode: ParseMode) -> anyhow::Result<ExitCode> {
+        let file = open(&self.path, mode)?;
+
+        match &file.source {
+            spike_spk::spk::Source::SingleFile(path) => {
+                println!("Format: single .spk file");
+                println!("   {} ({} bytes)", path.display(), path.metadata()?.len());
+            }
+            spike_spk::spk::Source::SplitSquashed {
+                pieces,
+                inner_path,
+                inner_size,
+            } => {
+                println!("Format: split SquashFS image ({} pieces)", pieces.len());
+                for piece in pieces {
+                    println!("   {} ({} bytes)", piece.display(), piece.metadata()?.len());
+                }
+                println!("Contains: {} ({inner_size} bytes)", inner_path.display());
+            }
+            spike_spk::spk::Source::Reader => println!("Format: unknown"),
+        }
+
+        for package in &file.packages {
+            println!();
+            println!("Package: {}", package.name);
+            println!(
+                "Version: {}.{}.{}",
+                package.version.0, package.version.1, package.version.2
+            );
+            println!("Type: {:?}", package.type_);
+            println!("Files: {}", package.files.len());
+            println!("Total size: {} bytes", package.total_size());
+            println!(
+                "File info chunks: {}",
+                if package.uses_fi64() { "FI64" } else { "FINF" }
+            );
+            if self.raw {
+                match package.sz64 {
+                    Some(sz64) => println!("SZ64: {sz64:#018x}"),
+                    None => println!("SZ64: none"),
+                }
+                println!("SIDX trailing bytes: {}", hex(&package.sidx_unknown));
+            }
+        }
+
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct InspectCommand {
+    /// The path to the SPK file to inspect.
+    ///
+    /// The path can be the path to a single .spk file, the path to a directory
+    /// containing the split update files (.spk.OOX.00{1,2,...}),
+    /// or the path to the first of the spilt update files (.spk.OON.000).
+    path: PathBuf,
+}
+
+/// Print the fields of `chunk`, including those whose meaning is unknown.
+fn print_chunk_fields(chunk: &spike_spk::raw::Chunk, indent: &str) {
+    use spike_spk::raw::ChunkData;
+
+    match &chunk.data {
+        ChunkData::SPKS { chunk_count } => println!("{indent}chunk_count: {chunk_count}"),
+        ChunkData::SIDX {
+            package_name,
+            version,
+            package_type,
+            unknown,
+        } => {
+            println!(
+                "{indent}package_name: {:?} ({})",
+                String::from_utf8_lossy(package_name).trim_end_matches('\0'),
+                hex(package_name)
+            );
+            println!("{indent}version: {}.{}.{}", version.0, version.1, version.2);
+            println!("{indent}package_type: {package_type:?}");
+            println!("{indent}unknown_b: {}", hex(unknown));
+        }
+        ChunkData::SZ64 { unknown } => {
+            println!(
+                "{indent}unknown: {unknown:#018x} ({})",
+                hex(&unknown.to_le_bytes())
+            );
+        }
+        ChunkData::STRS { string_data } => {
+            println!("{indent}string_data: {} bytes", string_data.len());
+        }
+        ChunkData::FINF(file_info) | ChunkData::FI64(file_info) => {
+            println!("{indent}filename: {}", file_info.filename);
+            println!("{indent}filename_offset: {}", file_info.filename_offset);
+            println!("{indent}file_size: {}", file_info.file_size);
+            println!("{indent}data_offset: {}", file_info.data_offset);
+            println!("{indent}data_size: {}", file_info.data_size);
+            println!("{indent}mode: {:o}", file_info.mode);
+            println!(
+                "{indent}pad_before_hmac: {}",
+                hex(&file_info.pad_before_hmac)
+            );
+            println!("{indent}data_hmac: {}", hex(&file_info.hmac));
+            println!("{indent}data_md5: {}", hex(&file_info.md5));
+            println!("{indent}pad_after_md5: {}", hex(&file_info.pad_after_md5));
+        }
+        _ => {}
+    }
+}
+
+impl Command for InspectCommand {
+    fn run(&self, mode: ParseMode) -> anyhow::Result<ExitCode> {
+        // Read the chunks without parsing the packages first, so that a file
+        // that cannot be parsed is still shown up to the point of the error.
+        let file = spike_spk::spk::UnparsedFile::open(&self.path)?;
+        let (root, error) = match spike_spk::raw::read_chunks_partial(file.reader()?, mode) {
+            Ok(root) => (Some(root), None),
+            Err(partial) => (partial.root, Some(partial.error)),
+        };
+
+        for (depth, chunk) in root.iter().flat_map(spike_spk::raw::Chunk::iter) {
+            let indent = "   ".repeat(depth);
+            println!(
+                "{indent}{} at {:#010x}: {:?} header ({} bytes), payload {} bytes",
+                chunk.name(),
+                chunk.offset,
+                chunk.header_form,
+                chunk.header_size(),
+                chunk.payload_len
+            );
+            print_chunk_fields(chunk, &format!("{indent}   - "));
+        }
+
+        if let Some(error) = error {
+            Err(error)?;
+        }
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct DiffCommand {
+    /// The path to the older SPK file.
+    old: PathBuf,
+
+    /// The path to the newer SPK file.
+    new: PathBuf,
+}
+
+impl Command for DiffCommand {
+    fn run(&self, mode: ParseMode) -> anyhow::Result<ExitCode> {
+        use spike_spk::diff::FileChange;
+
+        let old = open(&self.old, mode)?;
+        let new = open(&self.new, mode)?;
+        let diff = spike_spk::diff::diff(&old, &new);
+
+        let version = |version: Option<(u8, u8, u8)>| {
+            version.map_or_else(
+                || String::from("(none)"),
+                |version| format!("{}.{}.{}", version.0, version.1, version.2),
+            )
+        };
+
+        for (i, package) in diff.packages.iter().enumerate() {
+            if i > 0 {
+                println!("\n");
+            }
+
+            println!("Package: {}", package.name);
+            if package.old_version == package.new_version {
+                println!("Version: {}", version(package.new_version));
+            } else {
+                println!(
+                    "Version: {} -> {}",
+                    version(package.old_version),
+                    version(package.new_version)
+                );
+            }
+
+            let prefix = new
+                .packages
+                .iter()
+                .chain(&old.packages)
+                .find(|p| p.name == package.name)
+                .map_or("", |p| p.type_.path_prefix());
+
+            if package.files.is_empty() {
+                println!("No files changed");
+            }
+            for change in &package.files {
+                let path = format!("{prefix}{}", change.name());
+                match change {
+                    FileChange::Added(_) => println!("added         {path}"),
+                    FileChange::Removed(_) => println!("removed       {path}"),
+                    FileChange::Modified { old, new } => {
+                        let status = if change.contents_changed() {
+                            "modified"
+                        } else {
+                            "mode changed"
+                        };
+                        print!("{status:13} {path}");
+                        if change.mode_changed() {
+                            print!("  {} -> {}", mode_string(old.mode), mode_string(new.mode));
+                        }
+                        println!();
+                    }
+                }
+            }
+        }
+
+        let (mut added, mut removed, mut modified, mut mode_changed) = (0, 0, 0, 0);
+        for change in diff.packages.iter().flat_map(|package| &package.files) {
+            match change {
+                FileChange::Added(_) => added += 1,
+                FileChange::Removed(_) => removed += 1,
+                FileChange::Modified { .. } => {
+                    modified += usize::from(change.contents_changed());
+                    mode_changed += usize::from(change.mode_changed());
+                }
+            }
+        }
+        println!("\n");
+        println!(
+            "{added} added, {removed} removed, {modified} modified, {mode_changed} mode changed"
+        );
+
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct OverlayCommand {
+    /// The root of the file tree to apply the updates to, such as the
+    /// contents of an SD card image. It is modified in place.
+    base: PathBuf,
+
+    /// The SPK files to apply, in the order they were installed.
+    #[arg(required = true)]
+    updates: Vec<PathBuf>,
+}
+
+impl Command for OverlayCommand {
+    fn run(&self, mode: ParseMode) -> anyhow::Result<ExitCode> {
+        let updates = self
+            .updates
+            .iter()
+            .map(|path| open(path, mode))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let warnings = spike_spk::overlay::overlay(&self.base, &updates)?;
+        if !warnings.is_empty() {
+            eprintln!("\n");
+            for warning in &warnings {
+                eprintln!("Warning: {warning}");
+            }
+        }
+
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct InstallCommand {
+    /// The path to the SPK file to install.
+    ///
+    /// The path can be the path to a single .spk file, the path to a directory
+    /// containing the split update files (.spk.OOX.00{1,2,...}),
+    /// or the path to the first of the spilt update files (.spk.OON.000).
+    path: PathBuf,
+
+    /// The root of the file tree to install into.
+    root: PathBuf,
+}
+
+impl Command for InstallCommand {
+    fn run(&self, mode: ParseMode) -> anyhow::Result<ExitCode> {
+        let file = open(&self.path, mode)?;
+        spike_spk::install::install(&file, &self.root)?;
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct RollbackCommand {
+    /// The root of the file tree to roll back.
+    root: PathBuf,
+}
+
+impl Command for RollbackCommand {
+    fn run(&self, _: ParseMode) -> anyhow::Result<ExitCode> {
+        spike_spk::install::rollback(&self.root)?;
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct CatCommand {
+    /// The path to the SPK file containing the file.
+    ///
+    /// The path can be the path to a single .spk file, the path to a directory
+    /// containing the split update files (.spk.OOX.00{1,2,...}),
+    /// or the path to the first of the spilt update files (.spk.OON.000).
+    path: PathBuf,
+
+    /// The file to write, either as its name within its package or its full
+    /// path on the machine, e.g. `/games/jurassic_park_le/foo.hex`.
+    file: String,
+
+    /// Only look for the file within the package with this name.
+    #[arg(long, value_name = "NAME")]
+    package: Option<String>,
+}
+
+impl Command for CatCommand {
+    fn run(&self, mode: ParseMode) -> anyhow::Result<ExitCode> {
+        let file = open(&self.path, mode)?;
+
+        let matches = file
+            .find_files(&self.file)
+            .filter(|(package, _)| {
+                self.package
+                    .as_ref()
+                    .is_none_or(|name| &package.name == name)
+            })
+            .collect::<Vec<_>>();
+        let (_, file_info) = match matches.as_slice() {
+            [] => anyhow::bail!("No file named {}", self.file),
+            [found] => *found,
+            _ => anyhow::bail!(
+                "{} matches files in more than one package ({}). Pass --package to choose one.",
+                self.file,
+                matches
+                    .iter()
+                    .map(|(package, _)| package.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+
+        let report = spike_spk::verify::verify_file(&file, file_info);
+        if let Err(err) = &report.result {
+            eprintln!("Error: Failed to read {}: {err}", self.file);
+            return Ok(ExitCode::from(if err.is_io() {
+                EXIT_IO_FAILURE
+            } else {
+                EXIT_INTEGRITY_FAILURE
+            }));
+        }
+        if !report.passed() {
+            eprintln!(
+                "Error: {} failed verification (md5: {}  hmac: {})",
+                self.file,
+                check(report.md5_matches()),
+                check(report.hmac_matches())
+            );
+            return Ok(ExitCode::from(EXIT_INTEGRITY_FAILURE));
+        }
+
+        let mut stdout = std::io::stdout().lock();
+        file.copy_to(file_info, &mut stdout)?;
+        stdout.flush()?;
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct ExportCommand {
+    /// The path to the SPK file to export.
+    ///
+    /// The path can be the path to a single .spk file, the path to a directory
+    /// containing the split update files (.spk.OOX.00{1,2,...}),
+    /// or the path to the first of the spilt update files (.spk.OON.000).
+    path: PathBuf,
+
+    /// The file to write the archive to. If not specified, the archive is written to stdout.
+    #[arg(short, long, name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// How to arrange the files within the archive.
+    #[arg(long, value_enum, default_value_t)]
+    layout: ExportLayout,
+
+    /// Compress the archive with gzip. Implied by an output file name ending in .gz or .tgz.
+    #[arg(long)]
+    gzip: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ExportLayout {
+    /// A directory per package, as written by `extract`.
+    #[default]
+    Package,
+    /// The paths at which the files are installed on the machine.
+    Rootfs,
+}
+
+impl Command for ExportCommand {
+    fn run(&self, mode: ParseMode) -> anyhow::Result<ExitCode> {
+        let file = open(&self.path, mode)?;
+
+        match &self.output {
+            Some(path) => {
+                let writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+                if let Err(err) = self.write_archive(&file, writer) {
+                    let _ = std::fs::remove_file(path);
+                    return Err(err);
+                }
+            }
+            None if std::io::stdout().is_terminal() => {
+                anyhow::bail!(
+                    "Refusing to write a tar archive to a terminal. Pass --output or redirect stdout."
+                )
+            }
+            None => self.write_archive(&file, std::io::stdout().lock())?,
+        }
+
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+impl ExportCommand {
+    fn write_archive(
+        &self,
+        file: &spike_spk::SPKFile,
+        writer: impl std::io::Write,
+    ) -> anyhow::Result<()> {
+        let layout = match self.layout {
+            ExportLayout::Package => spike_spk::export::Layout::PerPackage,
+            ExportLayout::Rootfs => spike_spk::export::Layout::RootFs,
+        };
+
+        let gzip = self.gzip
+            || self.output.as_ref().is_some_and(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "gz" || extension == "tgz")
+            });
+        let mut writer = if gzip {
+            let encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+            spike_spk::export::export_tar(file, encoder, layout)?.finish()?
+        } else {
+            spike_spk::export::export_tar(file, writer, layout)?
+        };
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Format `mode` in the style of `ls -l`, e.g. `-rwxr-xr-x`.
+fn mode_string(mode: u16) -> String {
+    let kind = match mode & 0o170_000 {
+        0o100_000 => '-',
+        0o040_000 => 'd',
+        0o120_000 => 'l',
+        0o020_000 => 'c',
+        0o060_000 => 'b',
+        0o010_000 => 'p',
+        0o140_000 => 's',
+        _ => '?',
+    };
+
+    let mut string = String::from(kind);
+    for (shift, special, special_char) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+        let bits = mode >> shift;
+        string.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        string.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        string.push(match (bits & 0o1 != 0, mode & special != 0) {
+            (true, true) => special_char,
+            (false, true) => special_char.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    string
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    /// Human-readable text.
+    #[default]
+    Text,
+    /// A single JSON array containing one object per file.
+    Json,
+    /// One JSON object per line, per file.
+    Ndjson,
+    /// Comma-separated values with a header row.
+    Csv,
+}
+
+/// The machine-readable subset of [`OutputFormat`], written by [`write_records`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecordFormat {
+    Json,
+    Ndjson,
+    Csv,
+}
+
+impl OutputFormat {
+    /// The record format to write, or `None` for text that each command renders itself.
+    fn records(self) -> Option<RecordFormat> {
+        match self {
+            OutputFormat::Text => None,
+            OutputFormat::Json => Some(RecordFormat::Json),
+            OutputFormat::Ndjson => Some(RecordFormat::Ndjson),
+            OutputFormat::Csv => Some(RecordFormat::Csv),
+        }
+    }
+}
+
+/// A single file within a package, as written by the machine-readable output formats.
+#[derive(Debug, serde::Serialize)]
+struct FileRecord<'a> {
+    package: &'a str,
+    version: String,
+    package_type: String,
+    path: String,
+    mode: String,
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset: Option<u64>,
+    md5: String,
+    hmac: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    md5_matches: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hmac_matches: Option<bool>,
+    /// Only set for files that could not be verified.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl<'a> FileRecord<'a> {
+    fn new(
+        package: &'a str,
+        version: (u8, u8, u8),
+        package_type: spike_spk::PackageType,
+        file: &'a spike_spk::spk::FileInfo,
+    ) -> Self {
+        Self {
+            package,
+            version: format!("{}.{}.{}", version.0, version.1, version.2),
+            package_type: format!("{package_type:?}"),
+            path: format!("{}{}", package_type.path_prefix(), file.name),
+            mode: format!("{:o}", file.mode),
+            size: file.size,
+            offset: None,
+            md5: hex(&file.md5),
+            hmac: hex(&file.hmac),
+            status: None,
+            md5_matches: None,
+            hmac_matches: None,
+            error: None,
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+fn write_records<'a>(
+    format: RecordFormat,
+    records: impl Iterator<Item = FileRecord<'a>>,
+) -> anyhow::Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    match format {
+        RecordFormat::Json => {
+            serde_json::to_writer_pretty(&mut stdout, &records.collect::<Vec<_>>())?;
+            writeln!(stdout)?;
+        }
+        RecordFormat::Ndjson => {
+            for record in records {
+                serde_json::to_writer(&mut stdout, &record)?;
+                writeln!(stdout)?;
+            }
+        }
+        RecordFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(stdout);
+            for mut record in records {
+                // Every CSV row needs the same columns, so a verified file has an empty error.
+                if record.status.is_some() {
+                    record.error.get_or_insert_default();
+                }
+                writer.serialize(record)?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+fn print_verification_summary(
+    out: &mut dyn std::io::Write,
+    report: &spike_spk::verify::VerificationReport,
+) -> std::io::Result<()> {
+    let checked = report
+        .packages
+        .iter()
+        .map(|package| package.files.len())
+        .sum::<usize>();
+    let failed = report.failures().count();
+    let unreadable = report
+        .failures()
+        .filter(|(_, file)| read_failed(file))
+        .count();
+
+    write!(
+        out,
+        "Checked {checked} files: {} passed, {failed} failed",
+        checked - failed
+    )?;
+    if unreadable > 0 {
+        write!(out, " ({unreadable} could not be read)")?;
+    }
+    writeln!(out)?;
+
+    let affected = report
+        .packages
+        .iter()
+        .filter(|package| !package.passed())
+        .map(|package| package.name.as_str())
+        .collect::<Vec<_>>();
+    if !affected.is_empty() {
+        writeln!(out, "Packages with failures: {}", affected.join(", "))?;
+    }
+    Ok(())
+}
+
+fn check(value: bool) -> &'static str {
+    if value { "✔" } else { "✗" }
+}
+
+fn print_verification_report(report: &spike_spk::verify::VerificationReport) {
+    for (i, package) in report.packages.iter().enumerate() {
+        if i > 0 {
+            println!("\n");
+        }
+
+        println!("Package: {}", package.name);
+        println!(
+            "Version: {}.{}.{}",
+            package.version.0, package.version.1, package.version.2
+        );
+
+        for file in &package.files {
+            print!(
+                "{:165} mode={:o} size={:11}  md5: {}  hmac: {}  ",
+                format!("{}{}", package.type_.path_prefix(), file.file.name),
+                file.file.mode,
+                file.file.size,
+                check(file.md5_matches()),
+                check(file.hmac_matches())
+            );
+            match &file.result {
+                Ok(_) => println!(),
+                Err(err) => println!("error: {err}"),
+            }
+        }
     }
 }
 
@@ -69,6 +921,22 @@ struct ExtractCommand {
     /// If not specified, the files will be extracted to a directory alongside the SPK file.
     #[arg(short, long, name = "DIR")]
     output: Option<PathBuf>,
+
+    /// Replace the output directory if it already exists and is not empty.
+    #[arg(long)]
+    force: bool,
+
+    /// Only extract the package with this name. May be repeated.
+    #[arg(long = "package", value_name = "NAME")]
+    packages: Vec<String>,
+
+    /// Only extract files whose path within their package matches this glob. May be repeated.
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<glob::Pattern>,
+
+    /// Do not extract files whose path within their package matches this glob. May be repeated.
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<glob::Pattern>,
 }
 
 fn file_name_prefix(path: &Path) -> Option<&OsStr> {
@@ -81,9 +949,9 @@ fn file_name_prefix(path: &Path) -> Option<&OsStr> {
 }
 
 impl Command for ExtractCommand {
-    fn run(&self) -> anyhow::Result<()> {
+    fn run(&self, mode: ParseMode) -> anyhow::Result<ExitCode> {
         let path = std::path::absolute(&self.path)?;
-        let mut file = spike_spk::SPKFile::open(&path)?;
+        let mut file = open(&path, mode)?;
 
         let prefix = file_name_prefix(&path).ok_or_else(|| {
             anyhow::anyhow!(
@@ -99,12 +967,47 @@ impl Command for ExtractCommand {
             .map(|p| p.join(prefix))
             .ok_or_else(|| anyhow::anyhow!("No output directory specified and default output directory could not be computed"))?;
 
-        spike_spk::extract::extract(&mut file, &output_directory)
+        let options = spike_spk::extract::ExtractOptions {
+            force: self.force,
+            packages: self.packages.clone(),
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+        };
+        spike_spk::extract::extract(&mut file, &output_directory, &options)?;
+        Ok(ExitCode::SUCCESS)
     }
 }
 
-fn main() -> anyhow::Result<()> {
+/// Map errors that prevented a command from completing to an exit status.
+fn exit_code(err: &anyhow::Error) -> ExitCode {
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<spike_spk::spk::OpenError>() {
+            return ExitCode::from(if err.is_io() {
+                EXIT_IO_FAILURE
+            } else {
+                EXIT_PARSE_FAILURE
+            });
+        }
+        if cause.is::<std::io::Error>() {
+            return ExitCode::from(EXIT_IO_FAILURE);
+        }
+    }
+    ExitCode::FAILURE
+}
+
+fn main() -> ExitCode {
     let args = Args::parse();
 
-    args.command.run()
+    let mode = if args.lenient {
+        ParseMode::Lenient
+    } else {
+        ParseMode::Strict
+    };
+    match args.command.run(mode) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            exit_code(&err)
+        }
+    }
 }
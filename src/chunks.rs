@@ -1,8 +1,8 @@
-use binrw::{BinRead, FilePtr32, FilePtr64, NullString, binread};
+use binrw::{BinRead, BinWrite, FilePtr32, FilePtr64, NullString};
 use md5::digest::generic_array::GenericArray;
 
-#[derive(BinRead, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
-#[br(repr(u8))]
+#[derive(BinRead, BinWrite, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[brw(repr(u8))]
 pub enum PackageType {
     Spike1 = 1,
     Spike2 = 3,
@@ -10,7 +10,8 @@ pub enum PackageType {
 }
 
 impl PackageType {
-    pub(crate) fn path_prefix(&self) -> &str {
+    #[must_use]
+    pub fn path_prefix(&self) -> &str {
         if self == &PackageType::Game {
             "/games/"
         } else {
@@ -19,14 +20,22 @@ impl PackageType {
     }
 }
 
-#[derive(BinRead, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(BinRead, BinWrite, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub(crate) enum ByteLen {
-    #[br(magic = 0xffff_ffffu32)]
+    #[brw(magic = 0xffff_ffffu32)]
     New(u64),
     Old(u32),
 }
 
 impl ByteLen {
+    /// Use the legacy 32-bit form whenever the length can be represented by it.
+    pub(crate) fn new(byte_len: u64) -> Self {
+        match u32::try_from(byte_len) {
+            Ok(byte_len) if byte_len != u32::MAX => ByteLen::Old(byte_len),
+            _ => ByteLen::New(byte_len),
+        }
+    }
+
     pub(crate) fn byte_len(&self) -> u64 {
         match self {
             ByteLen::New(byte_len) => *byte_len,
@@ -43,27 +52,38 @@ impl ByteLen {
     }
 }
 
-#[derive(BinRead, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
-#[br(magic = b"SPKS")]
+#[derive(BinRead, BinWrite, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[brw(magic = b"SPKS")]
 pub(crate) struct SPKS {
-    byte_length: ByteLen,
+    pub byte_length: ByteLen,
     pub chunk_count: u32,
 }
 
-#[derive(BinRead, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
-#[br(magic = b"SPK0")]
+#[derive(BinRead, BinWrite, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[brw(magic = b"SPK0")]
 pub(crate) struct SPK0 {
-    byte_len: ByteLen,
+    pub byte_len: ByteLen,
+}
+
+impl SPKS {
+    pub(crate) fn new(byte_len: u64, chunk_count: u32) -> Self {
+        Self {
+            byte_length: ByteLen::new(byte_len),
+            chunk_count,
+        }
+    }
 }
 
 impl SPK0 {
-    pub(crate) fn offset_to_next(&self) -> u64 {
-        self.byte_len.header_size() + self.byte_len.byte_len()
+    pub(crate) fn new(byte_len: u64) -> Self {
+        Self {
+            byte_len: ByteLen::new(byte_len),
+        }
     }
 }
 
-#[derive(BinRead, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
-#[br(magic = b"SIDX")]
+#[derive(BinRead, BinWrite, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[brw(magic = b"SIDX")]
 pub(crate) struct SIDX {
     pub byte_len: ByteLen,
     pub package_name: [u8; 0x20],
@@ -71,17 +91,47 @@ pub(crate) struct SIDX {
     pub minor_version: u8,
     pub patch_version: u8,
     pub package_type: PackageType,
-    unknown_b: [u8; 0xc],
+    pub unknown_b: [u8; 0xc],
 }
 
-#[derive(BinRead, Clone, PartialEq, Eq, PartialOrd, Ord)]
-#[br(magic = b"STRS")]
+impl SIDX {
+    /// The number of bytes following the chunk header.
+    pub(crate) const BYTE_LEN: u64 = 0x20 + 4 + 0xc;
+
+    pub(crate) fn new(
+        package_name: [u8; 0x20],
+        version: (u8, u8, u8),
+        package_type: PackageType,
+    ) -> Self {
+        Self {
+            byte_len: ByteLen::new(Self::BYTE_LEN),
+            package_name,
+            major_version: version.0,
+            minor_version: version.1,
+            patch_version: version.2,
+            package_type,
+            unknown_b: [0; 0xc],
+        }
+    }
+}
+
+#[derive(BinRead, BinWrite, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[brw(magic = b"STRS")]
 pub(crate) struct STRS {
-    byte_len: u32,
+    pub byte_len: u32,
     #[br(count(byte_len))]
     pub string_data: Vec<u8>,
 }
 
+impl STRS {
+    pub(crate) fn new(string_data: Vec<u8>) -> Result<Self, std::num::TryFromIntError> {
+        Ok(Self {
+            byte_len: u32::try_from(string_data.len())?,
+            string_data,
+        })
+    }
+}
+
 impl std::fmt::Debug for STRS {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("STRS")
@@ -91,29 +141,35 @@ impl std::fmt::Debug for STRS {
     }
 }
 
-#[binread]
-#[derive(Clone, PartialEq, Eq)]
-#[br(magic = b"FINF", import(strs_offset: u64))]
+#[derive(BinRead, BinWrite, Clone, PartialEq, Eq)]
+#[brw(magic = b"FINF")]
+#[br(import(strs_offset: u64))]
 pub(crate) struct FINF {
-    byte_len: u32,
+    pub byte_len: u32,
     #[br(offset(strs_offset), parse_with = FilePtr32::parse, restore_position)]
-    filename: NullString,
+    #[bw(ignore)]
+    pub filename: NullString,
 
-    #[br(temp)]
-    _filename: u32,
+    // Relative to STRS.
+    pub filename_offset: u32,
 
-    file_size: u32,
+    pub file_size: u32,
 
     // Relative to SDAT.
-    data_offset: u32,
-    data_size: u32,
+    pub data_offset: u32,
+    pub data_size: u32,
+
+    pub mode: u16,
 
-    mode: u16,
+    pub pad_before_hmac: [u8; 3],
+    pub data_hmac: [u8; 20],
+    pub data_md5: [u8; 16],
+    pub pad_after_md5: [u8; 3],
+}
 
-    #[br(pad_before(3))]
-    data_hmac: [u8; 20],
-    #[br(pad_after(3))]
-    data_md5: [u8; 16],
+impl FINF {
+    /// The number of bytes following the `byte_len` field.
+    pub(crate) const BYTE_LEN: u32 = 4 * 4 + 2 + 3 + 20 + 16 + 3;
 }
 
 impl std::fmt::Debug for FINF {
@@ -121,10 +177,12 @@ impl std::fmt::Debug for FINF {
         f.debug_struct("FINF")
             .field("byte_len", &self.byte_len)
             .field("filename", &self.filename)
+            .field("filename_offset", &self.filename_offset)
             .field("file_size", &self.file_size)
             .field("data_offset", &self.data_offset)
             .field("data_size", &self.data_size)
             .field("mode", &self.mode)
+            .field("pad_before_hmac", &self.pad_before_hmac)
             .field(
                 "data_hmac",
                 &format_args!("{:02x}", GenericArray::from(self.data_hmac)),
@@ -133,21 +191,23 @@ impl std::fmt::Debug for FINF {
                 "data_md5",
                 &format_args!("{:02x}", GenericArray::from(self.data_md5)),
             )
+            .field("pad_after_md5", &self.pad_after_md5)
             .finish()
     }
 }
 
-#[binread]
-#[derive(Clone, PartialEq, Eq)]
-#[br(magic = b"FI64", import(strs_offset: u64))]
+#[derive(BinRead, BinWrite, Clone, PartialEq, Eq)]
+#[brw(magic = b"FI64")]
+#[br(import(strs_offset: u64))]
 pub(crate) struct FI64 {
-    byte_len: u32,
+    pub byte_len: u32,
 
     #[br(offset(strs_offset), parse_with = FilePtr64::parse, restore_position)]
+    #[bw(ignore)]
     pub filename: NullString,
 
-    #[br(temp)]
-    _filename: u64,
+    // Relative to STRS.
+    pub filename_offset: u64,
 
     pub file_size: u64,
 
@@ -157,10 +217,15 @@ pub(crate) struct FI64 {
 
     pub mode: u16,
 
-    #[br(pad_before(3))]
+    pub pad_before_hmac: [u8; 3],
     pub data_hmac: [u8; 20],
-    #[br(pad_after(7))]
     pub data_md5: [u8; 16],
+    pub pad_after_md5: [u8; 7],
+}
+
+impl FI64 {
+    /// The number of bytes following the `byte_len` field.
+    pub(crate) const BYTE_LEN: u32 = 8 * 4 + 2 + 3 + 20 + 16 + 7;
 }
 
 impl std::fmt::Debug for FI64 {
@@ -168,10 +233,12 @@ impl std::fmt::Debug for FI64 {
         f.debug_struct("FINF")
             .field("byte_len", &self.byte_len)
             .field("filename", &self.filename)
+            .field("filename_offset", &self.filename_offset)
             .field("file_size", &self.file_size)
             .field("data_offset", &self.data_offset)
             .field("data_size", &self.data_size)
             .field("mode", &self.mode)
+            .field("pad_before_hmac", &self.pad_before_hmac)
             .field(
                 "data_hmac",
                 &format_args!("{:02x}", GenericArray::from(self.data_hmac)),
@@ -180,42 +247,46 @@ impl std::fmt::Debug for FI64 {
                 "data_md5",
                 &format_args!("{:02x}", GenericArray::from(self.data_md5)),
             )
+            .field("pad_after_md5", &self.pad_after_md5)
             .finish()
     }
 }
 
-#[derive(BinRead, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
-#[br(magic = b"FEND")]
+#[derive(BinRead, BinWrite, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[brw(magic = b"FEND")]
 pub(crate) struct FEND {
     #[br(assert(byte_len == 0))]
     byte_len: u32,
 }
 
-#[derive(BinRead, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
-#[br(magic = b"SDAT")]
+impl FEND {
+    pub(crate) fn new() -> Self {
+        Self { byte_len: 0 }
+    }
+}
+
+#[derive(BinRead, BinWrite, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[brw(magic = b"SDAT")]
 pub(crate) struct SDAT {
-    byte_len: ByteLen,
+    pub byte_len: ByteLen,
 }
 
 impl SDAT {
-    #[allow(unused)]
-    pub(crate) fn byte_len(&self) -> u64 {
-        self.byte_len.byte_len()
-    }
-
-    pub(crate) fn header_size(&self) -> u64 {
-        self.byte_len.header_size()
+    pub(crate) fn new(byte_len: u64) -> Self {
+        Self {
+            byte_len: ByteLen::new(byte_len),
+        }
     }
 }
 
-#[derive(BinRead, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
-#[br(magic = b"SZ64")]
+#[derive(BinRead, BinWrite, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[brw(magic = b"SZ64")]
 pub(crate) struct SZ64 {
-    byte_len: u32,
-    unknown: u64,
+    pub byte_len: u32,
+    pub unknown: u64,
 }
 
-#[derive(BinRead, Debug, Clone, PartialEq, Eq)]
+#[derive(BinRead, BinWrite, Debug, Clone, PartialEq, Eq)]
 #[br(import(strs_offset: u64))]
 pub(crate) enum FileInfo {
     FINF(#[br(args(strs_offset))] FINF),
@@ -223,23 +294,242 @@ pub(crate) enum FileInfo {
     FEND(FEND),
 }
 
-impl TryFrom<FileInfo> for FI64 {
-    type Error = Box<dyn std::error::Error>;
-
-    fn try_from(file_info: FileInfo) -> Result<Self, Self::Error> {
-        match file_info {
-            FileInfo::FINF(finf) => Ok(FI64 {
-                byte_len: finf.byte_len,
-                filename: finf.filename,
-                file_size: u64::from(finf.file_size),
-                data_offset: u64::from(finf.data_offset),
-                data_size: u64::from(finf.data_size),
-                mode: finf.mode,
-                data_hmac: finf.data_hmac,
-                data_md5: finf.data_md5,
-            }),
-            FileInfo::FI64(fi64) => Ok(fi64),
-            FileInfo::FEND(_) => Err("FEND".into()),
-        }
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const NEW: [u8; 4] = [0xff; 4];
+
+    /// Read a `T` from `bytes`, check that all of them were consumed, and check
+    /// that writing it back produces exactly the same bytes.
+    fn round_trip<T>(bytes: &[u8]) -> T
+    where
+        T: for<'a> BinRead<Args<'a> = ()> + for<'a> BinWrite<Args<'a> = ()>,
+    {
+        round_trip_args(bytes, bytes.len(), ())
+    }
+
+    /// Like [`round_trip`], for a `T` made up of the first `len` of `bytes`
+    /// that may refer to data beyond them.
+    fn round_trip_args<T, A>(bytes: &[u8], len: usize, args: A) -> T
+    where
+        T: for<'a> BinRead<Args<'a> = A> + for<'a> BinWrite<Args<'a> = ()>,
+    {
+        let mut reader = Cursor::new(bytes);
+        let value = T::read_le_args(&mut reader, args).unwrap();
+        assert_eq!(reader.position(), len as u64, "unexpected chunk length");
+
+        let mut writer = Cursor::new(Vec::new());
+        value.write_le(&mut writer).unwrap();
+        assert_eq!(writer.into_inner(), &bytes[..len]);
+        value
+    }
+
+    #[test]
+    fn byte_len_forms() {
+        assert_eq!(
+            round_trip::<ByteLen>(&0x1234u32.to_le_bytes()),
+            ByteLen::Old(0x1234)
+        );
+        assert_eq!(
+            round_trip::<ByteLen>(&[NEW.as_slice(), &0x1_0000_0000u64.to_le_bytes()].concat()),
+            ByteLen::New(0x1_0000_0000)
+        );
+
+        assert_eq!(ByteLen::new(8), ByteLen::Old(8));
+        assert_eq!(ByteLen::new(0xffff_fffe), ByteLen::Old(0xffff_fffe));
+        // The largest 32-bit length would be read back as the marker of the new form.
+        assert_eq!(ByteLen::new(0xffff_ffff), ByteLen::New(0xffff_ffff));
+        assert_eq!(ByteLen::new(8).header_size(), 8);
+        assert_eq!(ByteLen::New(8).header_size(), 16);
+        assert_eq!(ByteLen::New(8).byte_len(), 8);
+    }
+
+    #[test]
+    fn spks() {
+        let spks =
+            round_trip::<SPKS>(&[b"SPKS".as_slice(), &[0x20, 0, 0, 0], &[2, 0, 0, 0]].concat());
+        assert_eq!(spks, SPKS::new(0x20, 2));
+
+        let spks = round_trip::<SPKS>(
+            &[
+                b"SPKS".as_slice(),
+                &NEW,
+                &0x20u64.to_le_bytes(),
+                &[3, 0, 0, 0],
+            ]
+            .concat(),
+        );
+        assert_eq!(spks.byte_length, ByteLen::New(0x20));
+        assert_eq!(spks.chunk_count, 3);
+    }
+
+    #[test]
+    fn spk0() {
+        let spk0 = round_trip::<SPK0>(&[b"SPK0".as_slice(), &[0x40, 0, 0, 0]].concat());
+        assert_eq!(spk0, SPK0::new(0x40));
+
+        let spk0 = round_trip::<SPK0>(&[b"SPK0".as_slice(), &NEW, &0x40u64.to_le_bytes()].concat());
+        assert_eq!(spk0.byte_len, ByteLen::New(0x40));
+    }
+
+    fn sidx_payload() -> Vec<u8> {
+        let mut name = [0; 0x20];
+        name[..5].copy_from_slice(b"spike");
+        [
+            name.as_slice(),
+            &[2, 7, 1, 3],
+            &[
+                0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xab,
+            ],
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn sidx() {
+        let payload = sidx_payload();
+        let sidx = round_trip::<SIDX>(&[b"SIDX".as_slice(), &[0x30, 0, 0, 0], &payload].concat());
+        assert_eq!(sidx.byte_len, ByteLen::Old(0x30));
+        assert_eq!(&sidx.package_name[..6], b"spike\0");
+        assert_eq!(
+            (sidx.major_version, sidx.minor_version, sidx.patch_version),
+            (2, 7, 1)
+        );
+        assert_eq!(sidx.package_type, PackageType::Spike2);
+        assert_eq!(sidx.unknown_b[0], 0xa0);
+        assert_eq!(sidx.unknown_b[0xb], 0xab);
+
+        let sidx = round_trip::<SIDX>(
+            &[b"SIDX".as_slice(), &NEW, &0x30u64.to_le_bytes(), &payload].concat(),
+        );
+        assert_eq!(sidx.byte_len, ByteLen::New(0x30));
+    }
+
+    #[test]
+    fn new_sidx_is_written_with_its_length() {
+        let mut writer = Cursor::new(Vec::new());
+        SIDX::new([0; 0x20], (1, 2, 3), PackageType::Game)
+            .write_le(&mut writer)
+            .unwrap();
+        let bytes = writer.into_inner();
+        assert_eq!(bytes.len() as u64, 8 + SIDX::BYTE_LEN);
+        assert_eq!(
+            bytes[4..8],
+            u32::try_from(SIDX::BYTE_LEN).unwrap().to_le_bytes()
+        );
+    }
+
+    #[test]
+    fn strs() {
+        let strs = round_trip::<STRS>(&[b"STRS".as_slice(), &[5, 0, 0, 0], b"ab\0c\0"].concat());
+        assert_eq!(strs.string_data, b"ab\0c\0");
+        assert_eq!(strs, STRS::new(b"ab\0c\0".to_vec()).unwrap());
+    }
+
+    /// A file info chunk followed by a string table, where the file name is at
+    /// offset 3 of the string table.
+    fn file_info_bytes(magic: &[u8], fields: &[u8], pad_after_md5: &[u8]) -> (Vec<u8>, usize) {
+        let body = [
+            fields,
+            &[0xa4, 0x81],
+            &[0x01, 0x02, 0x03],
+            &[0x11; 20],
+            &[0x22; 16],
+            pad_after_md5,
+        ]
+        .concat();
+        let chunk = [
+            magic,
+            &u32::try_from(body.len()).unwrap().to_le_bytes(),
+            &body,
+        ]
+        .concat();
+        let chunk_len = chunk.len();
+        ([chunk.as_slice(), b"xx\0etc/fstab\0"].concat(), chunk_len)
+    }
+
+    #[test]
+    fn finf() {
+        let fields = [3u32, 100, 200, 50]
+            .iter()
+            .flat_map(|field| field.to_le_bytes())
+            .collect::<Vec<_>>();
+        let (bytes, chunk_len) = file_info_bytes(b"FINF", &fields, &[0x04, 0x05, 0x06]);
+        let finf = round_trip_args::<FINF, _>(&bytes, chunk_len, (chunk_len as u64,));
+        assert_eq!(finf.filename.to_string(), "etc/fstab");
+
+        assert_eq!(finf.byte_len, FINF::BYTE_LEN);
+        assert_eq!(finf.filename_offset, 3);
+        assert_eq!(finf.file_size, 100);
+        assert_eq!(finf.data_offset, 200);
+        assert_eq!(finf.data_size, 50);
+        assert_eq!(finf.mode, 0o100_644);
+        assert_eq!(finf.pad_before_hmac, [0x01, 0x02, 0x03]);
+        assert_eq!(finf.data_hmac, [0x11; 20]);
+        assert_eq!(finf.data_md5, [0x22; 16]);
+        assert_eq!(finf.pad_after_md5, [0x04, 0x05, 0x06]);
+    }
+
+    #[test]
+    fn fi64() {
+        let fields = [3u64, 0x1_0000_0000, 0x2_0000_0000, 50]
+            .iter()
+            .flat_map(|field| field.to_le_bytes())
+            .collect::<Vec<_>>();
+        let pad_after_md5 = [0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a];
+        let (bytes, chunk_len) = file_info_bytes(b"FI64", &fields, &pad_after_md5);
+        let fi64 = round_trip_args::<FI64, _>(&bytes, chunk_len, (chunk_len as u64,));
+        assert_eq!(fi64.filename.to_string(), "etc/fstab");
+
+        assert_eq!(fi64.byte_len, FI64::BYTE_LEN);
+        assert_eq!(fi64.filename_offset, 3);
+        assert_eq!(fi64.file_size, 0x1_0000_0000);
+        assert_eq!(fi64.data_offset, 0x2_0000_0000);
+        assert_eq!(fi64.data_size, 50);
+        assert_eq!(fi64.mode, 0o100_644);
+        assert_eq!(fi64.pad_before_hmac, [0x01, 0x02, 0x03]);
+        assert_eq!(fi64.pad_after_md5, pad_after_md5);
+    }
+
+    #[test]
+    fn fend() {
+        let fend = round_trip::<FEND>(&[b"FEND".as_slice(), &[0, 0, 0, 0]].concat());
+        assert_eq!(fend, FEND::new());
+
+        let bytes = [b"FEND".as_slice(), &[1, 0, 0, 0]].concat();
+        assert!(FEND::read_le(&mut Cursor::new(bytes)).is_err());
+    }
+
+    #[test]
+    fn file_info_dispatches_on_magic() {
+        let bytes = [b"FEND".as_slice(), &[0, 0, 0, 0]].concat();
+        let file_info = round_trip_args::<FileInfo, _>(&bytes, bytes.len(), (0,));
+        assert_eq!(file_info, FileInfo::FEND(FEND::new()));
+    }
+
+    #[test]
+    fn sdat() {
+        let sdat = round_trip::<SDAT>(&[b"SDAT".as_slice(), &[0x10, 0, 0, 0]].concat());
+        assert_eq!(sdat, SDAT::new(0x10));
+
+        let sdat = round_trip::<SDAT>(&[b"SDAT".as_slice(), &NEW, &0x10u64.to_le_bytes()].concat());
+        assert_eq!(sdat.byte_len, ByteLen::New(0x10));
+    }
+
+    #[test]
+    fn sz64() {
+        let sz64 = round_trip::<SZ64>(
+            &[
+                b"SZ64".as_slice(),
+                &[8, 0, 0, 0],
+                &0x1122_3344_5566_7788u64.to_le_bytes(),
+            ]
+            .concat(),
+        );
+        assert_eq!(sz64.byte_len, 8);
+        assert_eq!(sz64.unknown, 0x1122_3344_5566_7788);
     }
 }
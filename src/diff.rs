@@ -0,0 +1,279 @@
+use std::collections::BTreeMap;
+
+use crate::spk;
+
+/// The differences between two SPK files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diff {
+    /// Every package present in either file, in the order they appear in the
+    /// new file followed by any that were removed.
+    pub packages: Vec<PackageDiff>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageDiff {
+    pub name: String,
+    /// The package's version in the old file, or `None` if the package was added.
+    pub old_version: Option<(u8, u8, u8)>,
+    /// The package's version in the new file, or `None` if the package was removed.
+    pub new_version: Option<(u8, u8, u8)>,
+    /// Sorted by file name. Files that are identical in both are omitted.
+    pub files: Vec<FileChange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileChange {
+    Added(spk::FileInfo),
+    Removed(spk::FileInfo),
+    /// The file's contents, mode, or both differ.
+    Modified {
+        old: spk::FileInfo,
+        new: spk::FileInfo,
+    },
+}
+
+impl Diff {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.packages.iter().all(PackageDiff::is_unchanged)
+    }
+}
+
+impl PackageDiff {
+    /// Whether the package has the same version and files in both SPK files.
+    #[must_use]
+    pub fn is_unchanged(&self) -> bool {
+        self.old_version == self.new_version && self.files.is_empty()
+    }
+}
+
+impl FileChange {
+    #[must_use]
+    pub fn name(&self) -> &str {
+        match self {
+            FileChange::Added(file) | FileChange::Removed(file) => &file.name,
+            FileChange::Modified { new, .. } => &new.name,
+        }
+    }
+
+    /// Whether the file's contents differ, judged by their MD5.
+    #[must_use]
+    pub fn contents_changed(&self) -> bool {
+        match self {
+            FileChange::Added(_) | FileChange::Removed(_) => true,
+            FileChange::Modified { old, new } => old.md5 != new.md5,
+        }
+    }
+
+    #[must_use]
+    pub fn mode_changed(&self) -> bool {
+        match self {
+            FileChange::Added(_) | FileChange::Removed(_) => false,
+            FileChange::Modified { old, new } => old.mode != new.mode,
+        }
+    }
+}
+
+fn diff_files(old: &[spk::FileInfo], new: &[spk::FileInfo]) -> Vec<FileChange> {
+    let mut files = BTreeMap::new();
+    for file in old {
+        files.insert(file.name.as_str(), (Some(file), None));
+    }
+    for file in new {
+        files.entry(file.name.as_str()).or_insert((None, None)).1 = Some(file);
+    }
+
+    files
+        .into_values()
+        .filter_map(|files| match files {
+            (None, Some(new)) => Some(FileChange::Added(new.clone())),
+            (Some(old), None) => Some(FileChange::Removed(old.clone())),
+            (Some(old), Some(new)) if old.md5 != new.md5 || old.mode != new.mode => {
+                Some(FileChange::Modified {
+                    old: old.clone(),
+                    new: new.clone(),
+                })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Compare two SPK files, matching packages by name and files by path.
+///
+/// Only the metadata of each file is compared, so no file data is read.
+#[must_use]
+pub fn diff(old: &spk::SPKFile, new: &spk::SPKFile) -> Diff {
+    let find_old = |name: &str| old.packages.iter().find(|package| package.name == name);
+    let find_new = |name: &str| new.packages.iter().find(|package| package.name == name);
+
+    let mut packages = new
+        .packages
+        .iter()
+        .map(|new_package| {
+            let old_package = find_old(&new_package.name);
+            PackageDiff {
+                name: new_package.name.clone(),
+                old_version: old_package.map(|package| package.version),
+                new_version: Some(new_package.version),
+                files: diff_files(
+                    old_package.map_or(&[], |package| &package.files),
+                    &new_package.files,
+                ),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    packages.extend(
+        old.packages
+            .iter()
+            .filter(|old_package| find_new(&old_package.name).is_none())
+            .map(|old_package| PackageDiff {
+                name: old_package.name.clone(),
+                old_version: Some(old_package.version),
+                new_version: None,
+                files: diff_files(&old_package.files, &[]),
+            }),
+    );
+
+    Diff { packages }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, Entry, Package};
+
+    fn parse(packages: &[Package]) -> spk::SPKFile<'static> {
+        spk::SPKFile::parse_bytes(testutil::spk(packages)).unwrap()
+    }
+
+    fn names(changes: &[FileChange]) -> Vec<(&'static str, &str)> {
+        changes
+            .iter()
+            .map(|change| {
+                let kind = match change {
+                    FileChange::Added(_) => "added",
+                    FileChange::Removed(_) => "removed",
+                    FileChange::Modified { .. } => "modified",
+                };
+                (kind, change.name())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn identical_files_have_no_differences() {
+        let package = || testutil::package("game", vec![Entry::file("a", b"contents")]);
+        let diff = diff(&parse(&[package()]), &parse(&[package()]));
+        assert!(diff.is_empty());
+        assert_eq!(diff.packages.len(), 1);
+        assert!(diff.packages[0].files.is_empty());
+    }
+
+    #[test]
+    fn files_are_added_removed_and_modified() {
+        let old = parse(&[testutil::package(
+            "game",
+            vec![
+                Entry::file("contents", b"old"),
+                Entry::with_mode("mode", 0o100_644, b"same"),
+                Entry::file("removed", b"removed"),
+                Entry::file("unchanged", b"unchanged"),
+            ],
+        )]);
+        let new = parse(&[testutil::package(
+            "game",
+            vec![
+                Entry::file("added", b"added"),
+                Entry::file("contents", b"new"),
+                Entry::with_mode("mode", 0o100_755, b"same"),
+                Entry::file("unchanged", b"unchanged"),
+            ],
+        )]);
+
+        let diff = diff(&old, &new);
+        assert!(!diff.is_empty());
+        let [package] = &diff.packages[..] else {
+            panic!("expected one package");
+        };
+        assert_eq!(package.old_version, Some((1, 0, 0)));
+        assert_eq!(package.new_version, Some((1, 0, 0)));
+        assert!(!package.is_unchanged());
+        assert_eq!(
+            names(&package.files),
+            [
+                ("added", "added"),
+                ("modified", "contents"),
+                ("modified", "mode"),
+                ("removed", "removed"),
+            ]
+        );
+
+        let contents = &package.files[1];
+        assert!(contents.contents_changed());
+        assert!(!contents.mode_changed());
+        let mode = &package.files[2];
+        assert!(!mode.contents_changed());
+        assert!(mode.mode_changed());
+        let FileChange::Modified { old, new } = mode else {
+            panic!("expected a modified file");
+        };
+        assert_eq!((old.mode, new.mode), (0o100_644, 0o100_755));
+    }
+
+    #[test]
+    fn packages_are_added_and_removed() {
+        let old = parse(&[
+            testutil::package("kept", vec![Entry::file("a", b"a")]),
+            testutil::package("removed", vec![Entry::file("b", b"b")]),
+        ]);
+        let new = parse(&[
+            testutil::package("added", vec![Entry::file("c", b"c")]),
+            testutil::package("kept", vec![Entry::file("a", b"a")]),
+        ]);
+
+        let diff = diff(&old, &new);
+        let [added, kept, removed] = &diff.packages[..] else {
+            panic!("expected three packages");
+        };
+
+        assert_eq!(added.name, "added");
+        assert_eq!(
+            (added.old_version, added.new_version),
+            (None, Some((1, 0, 0)))
+        );
+        assert_eq!(names(&added.files), [("added", "c")]);
+
+        assert_eq!(kept.name, "kept");
+        assert!(kept.is_unchanged());
+
+        assert_eq!(removed.name, "removed");
+        assert_eq!(
+            (removed.old_version, removed.new_version),
+            (Some((1, 0, 0)), None)
+        );
+        assert_eq!(names(&removed.files), [("removed", "b")]);
+    }
+
+    #[test]
+    fn version_changes_are_reported_without_file_changes() {
+        let package = |version| Package {
+            version,
+            ..testutil::package("game", vec![Entry::file("a", b"contents")])
+        };
+
+        let diff = diff(
+            &parse(&[package((1, 14, 0))]),
+            &parse(&[package((1, 15, 0))]),
+        );
+        assert!(!diff.is_empty());
+        let [package] = &diff.packages[..] else {
+            panic!("expected one package");
+        };
+        assert_eq!(package.old_version, Some((1, 14, 0)));
+        assert_eq!(package.new_version, Some((1, 15, 0)));
+        assert!(package.files.is_empty());
+        assert!(!package.is_unchanged());
+    }
+}
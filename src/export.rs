@@ -0,0 +1,263 @@
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    io::Write,
+    os::unix::ffi::OsStrExt as _,
+    path::{Path, PathBuf},
+};
+
+use crate::{sanitize, spk, verify};
+
+/// How the files within a SPK file are arranged within an archive.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// A directory per package, as written by [`crate::extract::extract`].
+    #[default]
+    PerPackage,
+    /// The paths at which the files are installed on the machine, as given by
+    /// [`crate::PackageType::path_prefix`].
+    RootFs,
+}
+
+/// Every entry is owned by root and has a modification time of the epoch, so
+/// that exporting the same SPK file always produces the same archive.
+fn header(entry_type: tar::EntryType, mode: u32, size: u64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_mode(mode);
+    header.set_size(size);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(0);
+    header
+}
+
+/// Add entries for each of the directories containing `path` that have not
+/// already been added.
+fn append_parents<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &Path,
+    added: &mut HashSet<PathBuf>,
+) -> std::io::Result<()> {
+    let mut parents = path
+        .ancestors()
+        .skip(1)
+        .take_while(|parent| !parent.as_os_str().is_empty())
+        .collect::<Vec<_>>();
+    parents.reverse();
+
+    for parent in parents {
+        if added.insert(parent.to_path_buf()) {
+            let mut header = header(tar::EntryType::Directory, 0o755, 0);
+            builder.append_data(&mut header, parent, std::io::empty())?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the files within `file` to `writer` as a tar archive, preserving each
+/// file's mode. Every file is verified before anything is written.
+///
+/// Returns `writer` once the archive is complete, so that a compressor wrapping
+/// it can be finished.
+pub fn export_tar<W: Write>(file: &spk::SPKFile, writer: W, layout: Layout) -> anyhow::Result<W> {
+    match layout {
+        Layout::PerPackage => {
+            for package in &file.packages {
+                sanitize::validate_packages(std::slice::from_ref(package))?;
+            }
+        }
+        Layout::RootFs => sanitize::validate_packages(&file.packages)?,
+    }
+    for file_info in file.packages.iter().flat_map(|package| &package.files) {
+        file_info.kind()?;
+    }
+    verify::verify_all(file, &file.packages)?;
+
+    let path = |package: &spk::Package, file_info: &spk::FileInfo| match layout {
+        Layout::PerPackage => Path::new(&package.name).join(&file_info.name),
+        Layout::RootFs => package.install_path(file_info),
+    };
+
+    let mut builder = tar::Builder::new(writer);
+    let mut added = HashSet::new();
+
+    // Directory entries are added before any files, so that a directory is not
+    // first added with a default mode as the parent of a file within it. Sorting
+    // by path adds each directory after its parents.
+    let mut directories = Vec::new();
+    for package in &file.packages {
+        for file_info in &package.files {
+            if file_info.kind()? == spk::FileKind::Directory {
+                directories.push((path(package, file_info), file_info));
+            }
+        }
+    }
+    directories.sort_by(|a, b| a.0.cmp(&b.0));
+    for (path, file_info) in directories {
+        append_parents(&mut builder, &path, &mut added)?;
+        // A directory in more than one package is added once.
+        if added.insert(path.clone()) {
+            let mut header = header(tar::EntryType::Directory, file_info.permissions(), 0);
+            builder.append_data(&mut header, &path, std::io::empty())?;
+        }
+    }
+
+    for package in &file.packages {
+        let mut files = package.files.iter().collect::<Vec<_>>();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for file_info in files {
+            let path = path(package, file_info);
+            match file_info.kind()? {
+                spk::FileKind::Regular => {
+                    append_parents(&mut builder, &path, &mut added)?;
+                    let mut header = header(
+                        tar::EntryType::Regular,
+                        file_info.permissions(),
+                        file_info.size,
+                    );
+                    builder.append_data(&mut header, &path, file.contents_reader(file_info)?)?;
+                }
+                // Added along with the other directories above.
+                spk::FileKind::Directory => {}
+                spk::FileKind::Symlink => {
+                    append_parents(&mut builder, &path, &mut added)?;
+                    let target = file.read(file_info)?;
+                    let mut header = header(tar::EntryType::Symlink, file_info.permissions(), 0);
+                    builder.append_link(&mut header, &path, OsStr::from_bytes(&target))?;
+                }
+            }
+        }
+    }
+
+    Ok(builder.into_inner()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read as _;
+
+    use super::*;
+    use crate::testutil::{self, Entry};
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct ArchiveEntry {
+        path: String,
+        kind: tar::EntryType,
+        mode: u32,
+        /// The entry's data, or the target of a symlink.
+        contents: String,
+    }
+
+    fn export(data: Vec<u8>, layout: Layout) -> Vec<u8> {
+        let file = spk::SPKFile::parse_bytes(data).unwrap();
+        export_tar(&file, Vec::new(), layout).unwrap()
+    }
+
+    fn entries(archive: &[u8]) -> Vec<ArchiveEntry> {
+        tar::Archive::new(archive)
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let header = entry.header();
+                let (kind, mode) = (header.entry_type(), header.mode().unwrap());
+                let path = entry.path().unwrap().to_str().unwrap().to_string();
+                let mut contents = String::new();
+                if let Some(target) = entry.link_name().unwrap() {
+                    contents = target.to_str().unwrap().to_string();
+                } else {
+                    entry.read_to_string(&mut contents).unwrap();
+                }
+                ArchiveEntry {
+                    path,
+                    kind,
+                    mode,
+                    contents,
+                }
+            })
+            .collect()
+    }
+
+    fn entry(path: &str, kind: tar::EntryType, mode: u32, contents: &str) -> ArchiveEntry {
+        ArchiveEntry {
+            path: path.to_string(),
+            kind,
+            mode,
+            contents: contents.to_string(),
+        }
+    }
+
+    fn spk() -> Vec<u8> {
+        testutil::spk(&[testutil::package(
+            "game",
+            vec![
+                Entry::with_mode("bin/run", 0o100_750, b"#!/bin/sh\n"),
+                Entry::with_mode("data", 0o040_700, b""),
+                Entry::file("data/file", b"contents"),
+                Entry::symlink("link", "data/file"),
+            ],
+        )])
+    }
+
+    #[test]
+    fn exporting_the_same_file_twice_gives_the_same_archive() {
+        let first = export(spk(), Layout::RootFs);
+        let second = export(spk(), Layout::RootFs);
+        assert!(!first.is_empty());
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn per_package_layout_keeps_modes_and_symlink_targets() {
+        use tar::EntryType::{Directory, Regular, Symlink};
+
+        assert_eq!(
+            entries(&export(spk(), Layout::PerPackage)),
+            [
+                entry("game", Directory, 0o755, ""),
+                entry("game/data", Directory, 0o700, ""),
+                entry("game/bin", Directory, 0o755, ""),
+                entry("game/bin/run", Regular, 0o750, "#!/bin/sh\n"),
+                entry("game/data/file", Regular, 0o644, "contents"),
+                entry("game/link", Symlink, 0o777, "data/file"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rootfs_layout_keeps_modes_and_symlink_targets() {
+        use tar::EntryType::{Directory, Regular, Symlink};
+
+        assert_eq!(
+            entries(&export(spk(), Layout::RootFs)),
+            [
+                entry("games", Directory, 0o755, ""),
+                entry("games/data", Directory, 0o700, ""),
+                entry("games/bin", Directory, 0o755, ""),
+                entry("games/bin/run", Regular, 0o750, "#!/bin/sh\n"),
+                entry("games/data/file", Regular, 0o644, "contents"),
+                entry("games/link", Symlink, 0o777, "data/file"),
+            ]
+        );
+    }
+
+    #[test]
+    fn directories_keep_their_mode_when_a_file_within_them_comes_first() {
+        use tar::EntryType::{Directory, Regular};
+
+        let data = testutil::spk(&[
+            testutil::package("first", vec![Entry::file("shared/file", b"contents")]),
+            testutil::package("second", vec![Entry::with_mode("shared", 0o040_750, b"")]),
+        ]);
+        assert_eq!(
+            entries(&export(data, Layout::RootFs)),
+            [
+                entry("games", Directory, 0o755, ""),
+                entry("games/shared", Directory, 0o750, ""),
+                entry("games/shared/file", Regular, 0o644, "contents"),
+            ]
+        );
+    }
+}
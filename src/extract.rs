@@ -1,62 +1,498 @@
-use std::{io::Write as _, path::Path};
+use std::{
+    ffi::{OsStr, OsString},
+    io::Write as _,
+    os::unix::ffi::OsStrExt as _,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Context as _;
 
-use crate::{spk, verify};
+use crate::{sanitize, spk, verify};
 
-pub fn extract(file: &mut spk::SPKFile, to: &Path) -> anyhow::Result<()> {
-    match std::fs::remove_dir_all(to) {
-        Ok(()) => {}
-        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
-        err @ Err(_) => err.with_context(|| {
-            format!(
-                "Failed to remove directory prior to extraction {}",
-                to.display()
-            )
-        })?,
+#[derive(Debug, Default, Clone)]
+pub struct ExtractOptions {
+    /// Replace the destination directory if it already exists and is not empty.
+    pub force: bool,
+    /// The names of the packages to extract, or empty to extract every package.
+    pub packages: Vec<String>,
+    /// Only extract files whose name within their package matches one of
+    /// these patterns, or every file if empty.
+    pub include: Vec<glob::Pattern>,
+    /// Do not extract files whose name within their package matches one of these patterns.
+    pub exclude: Vec<glob::Pattern>,
+}
+
+impl ExtractOptions {
+    fn selects(&self, file: &spk::FileInfo) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| p.matches(&file.name)))
+            && !self.exclude.iter().any(|p| p.matches(&file.name))
+    }
+
+    /// The packages and files within `file` that are selected by these options.
+    /// Packages with no selected files are omitted.
+    fn select(&self, file: &spk::SPKFile) -> anyhow::Result<Vec<spk::Package>> {
+        for name in &self.packages {
+            if !file.packages.iter().any(|package| &package.name == name) {
+                anyhow::bail!("No package named {name}");
+            }
+        }
+
+        let packages = file
+            .packages
+            .iter()
+            .filter(|package| self.packages.is_empty() || self.packages.contains(&package.name))
+            .filter_map(|package| {
+                let files = package
+                    .files
+                    .iter()
+                    .filter(|file| self.selects(file))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                (!files.is_empty()).then(|| spk::Package {
+                    files,
+                    ..package.clone()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        if packages.is_empty() {
+            anyhow::bail!("No files matched the selected packages and patterns");
+        }
+        Ok(packages)
+    }
+}
+
+/// A path alongside `to` with the given suffix, such that renaming between the
+/// two stays within one file system.
+fn sibling_path(to: &Path, suffix: &str) -> anyhow::Result<PathBuf> {
+    let name = to
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("Invalid extraction directory {}", to.display()))?;
+    let mut sibling_name = OsString::from(".");
+    sibling_name.push(name);
+    sibling_name.push(format!(".{suffix}-{}", std::process::id()));
+    Ok(to.with_file_name(sibling_name))
+}
+
+fn is_non_empty_dir(path: &Path) -> std::io::Result<bool> {
+    match std::fs::read_dir(path) {
+        Ok(mut entries) => Ok(entries.next().is_some()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Extract the files within `file` that are selected by `options` into a
+/// directory per package within `to`. Files that are not selected are neither
+/// verified nor read.
+///
+/// The files are verified and extracted into a temporary directory alongside
+/// `to`, which is only renamed into place once every file has been written.
+/// An existing non-empty directory at `to` is left untouched unless
+/// [`ExtractOptions::force`] is set.
+pub fn extract(file: &mut spk::SPKFile, to: &Path, options: &ExtractOptions) -> anyhow::Result<()> {
+    if !options.force && is_non_empty_dir(to)? {
+        anyhow::bail!(
+            "Refusing to replace non-empty directory {} without --force",
+            to.display()
+        );
+    }
+
+    let packages = options.select(file)?;
+
+    // Each package is extracted to its own directory.
+    for package in &packages {
+        sanitize::validate_packages(std::slice::from_ref(package))
+            .with_context(|| format!("Refusing to extract package {}", package.name))?;
+        for file_info in &package.files {
+            file_info
+                .kind()
+                .with_context(|| format!("Refusing to extract package {}", package.name))?;
+        }
     }
 
     print!("Verifying contents of file...");
     std::io::stdout().flush()?;
-    verify::verify_all(file)?;
+    verify::verify_all(file, &packages)?;
     println!(" done!");
 
-    for package in &file.packages {
+    let partial = sibling_path(to, "partial")?;
+    if let Err(err) = extract_packages(file, &packages, &partial, to) {
+        let _ = std::fs::remove_dir_all(&partial);
+        return Err(err);
+    }
+
+    replace_dir(&partial, to).inspect_err(|_| {
+        let _ = std::fs::remove_dir_all(&partial);
+    })
+}
+
+/// Move the directory at `from` to `to`, replacing anything already at `to`.
+fn replace_dir(from: &Path, to: &Path) -> anyhow::Result<()> {
+    let previous = sibling_path(to, "previous")?;
+    let had_previous = match std::fs::rename(to, &previous) {
+        Ok(()) => true,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => false,
+        Err(err) => Err(err)
+            .with_context(|| format!("Failed to move aside existing directory {}", to.display()))?,
+    };
+
+    if let Err(err) = std::fs::rename(from, to) {
+        if had_previous {
+            let _ = std::fs::rename(&previous, to);
+        }
+        return Err(err)
+            .with_context(|| format!("Failed to move extracted files to {}", to.display()));
+    }
+
+    // The new directory is already in place, so a leftover previous one is not an error.
+    if had_previous && let Err(err) = std::fs::remove_dir_all(&previous) {
+        eprintln!(
+            "Warning: Failed to remove previous extraction {}: {err}",
+            previous.display()
+        );
+    }
+    Ok(())
+}
+
+/// Write the files within `packages` to `to`, describing them as being extracted to `destination`.
+fn extract_packages(
+    file: &spk::SPKFile,
+    packages: &[spk::Package],
+    to: &Path,
+    destination: &Path,
+) -> anyhow::Result<()> {
+    match std::fs::remove_dir_all(to) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        err @ Err(_) => {
+            err.with_context(|| format!("Failed to remove stale directory {}", to.display()))?;
+        }
+    }
+    std::fs::create_dir_all(to)?;
+
+    let mut directories = Vec::new();
+    for package in packages {
         println!("\n");
 
         let package_path = to.join(&package.name);
         println!(
             "Extracting package {} to {}",
             package.name,
-            package_path.display()
+            destination.join(&package.name).display()
         );
 
         for file_info in &package.files {
-            if file_info.name.starts_with('/') {
-                anyhow::bail!(
-                    "Refusing to extract file whose path is absolute: {}",
-                    file_info.name
-                );
+            println!("   {}", file_info.name);
+            let output_path = sanitize::resolve(&package_path, Path::new(&file_info.name))?;
+            if write_entry(file, file_info, &output_path)? == spk::FileKind::Directory {
+                directories.push((output_path, file_info.permissions()));
             }
+        }
+    }
 
-            println!("   {}", file_info.name);
-            let output_path = package_path.join(&file_info.name);
-            let parent = output_path.parent().ok_or_else(|| {
-                anyhow::anyhow!(
-                    "Failed to get parent directory for {}",
-                    output_path.display()
-                )
-            })?;
+    set_directory_permissions(&directories)
+}
+
+/// Write `file_info` to `path` as the kind of file given by its mode,
+/// returning that kind.
+///
+/// The permissions of directories are not set, so that files can be written
+/// within them first. Pass them to [`set_directory_permissions`] once done.
+pub(crate) fn write_entry(
+    file: &spk::SPKFile,
+    file_info: &spk::FileInfo,
+    path: &Path,
+) -> anyhow::Result<spk::FileKind> {
+    let kind = file_info.kind()?;
+    let parent = path
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get parent directory for {}", path.display()))?;
+    std::fs::create_dir_all(parent)?;
 
-            std::fs::create_dir_all(parent)?;
+    // Never write through an existing symbolic link, which could point anywhere.
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove existing {}", path.display()))?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => Err(err)?,
+    }
 
-            std::fs::write(&output_path, file.read(file_info)?)?;
-            std::fs::set_permissions(
-                &output_path,
-                std::os::unix::fs::PermissionsExt::from_mode(u32::from(file_info.mode)),
-            )?;
+    match kind {
+        spk::FileKind::Regular => {
+            let mut output = std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+            file.copy_to(file_info, &mut output)?;
+            output.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(
+                file_info.permissions(),
+            ))?;
+        }
+        spk::FileKind::Directory => std::fs::create_dir_all(path)?,
+        spk::FileKind::Symlink => {
+            let target = file.read(file_info)?;
+            std::os::unix::fs::symlink(OsStr::from_bytes(&target), path)
+                .with_context(|| format!("Failed to create symbolic link {}", path.display()))?;
         }
     }
+    Ok(kind)
+}
 
+/// Set the permissions of directories written by [`write_entry`], deepest first
+/// so that a directory is never made read-only before its children are done.
+pub(crate) fn set_directory_permissions(directories: &[(PathBuf, u32)]) -> anyhow::Result<()> {
+    let mut directories = directories.iter().collect::<Vec<_>>();
+    directories.sort_by_key(|(path, _)| std::cmp::Reverse(path.components().count()));
+    for (path, permissions) in directories {
+        std::fs::set_permissions(
+            path,
+            std::os::unix::fs::PermissionsExt::from_mode(*permissions),
+        )?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, Entry};
+
+    #[test]
+    fn refuses_a_symlink_followed_by_a_file_of_the_same_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = dir.path().join("outside");
+        std::fs::write(&outside, "untouched").unwrap();
+
+        let data = testutil::spk(&[testutil::package(
+            "game",
+            vec![
+                Entry::symlink("link", outside.to_str().unwrap()),
+                Entry::file("link", b"overwritten"),
+            ],
+        )]);
+        let mut file = spk::SPKFile::parse_bytes(data).unwrap();
+        let to = dir.path().join("extracted");
+        let err = extract(&mut file, &to, &ExtractOptions::default()).unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<sanitize::PathError>(),
+            Some(sanitize::PathError::Duplicate(_))
+        ));
+        assert_eq!(std::fs::read_to_string(&outside).unwrap(), "untouched");
+        assert!(!to.exists());
+    }
+
+    #[test]
+    fn write_entry_replaces_rather_than_follows_a_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = dir.path().join("outside");
+        std::fs::write(&outside, "untouched").unwrap();
+        let path = dir.path().join("link");
+        std::os::unix::fs::symlink(&outside, &path).unwrap();
+
+        let data = testutil::spk(&[testutil::package(
+            "game",
+            vec![Entry::with_mode("link", 0o100_600, b"written")],
+        )]);
+        let file = spk::SPKFile::parse_bytes(data).unwrap();
+        let kind = write_entry(&file, &file.packages[0].files[0], &path).unwrap();
+
+        assert_eq!(kind, spk::FileKind::Regular);
+        assert_eq!(std::fs::read_to_string(&outside).unwrap(), "untouched");
+        let metadata = std::fs::symlink_metadata(&path).unwrap();
+        assert!(metadata.is_file());
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o777,
+            0o600
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "written");
+    }
+
+    #[test]
+    fn extracts_symlinks_and_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = testutil::spk(&[testutil::package(
+            "game",
+            vec![
+                Entry::with_mode("data", 0o040_750, b""),
+                Entry::file("data/file", b"contents"),
+                Entry::symlink("link", "data/file"),
+            ],
+        )]);
+        let mut file = spk::SPKFile::parse_bytes(data).unwrap();
+        let to = dir.path().join("extracted");
+        extract(&mut file, &to, &ExtractOptions::default()).unwrap();
+
+        let metadata = std::fs::symlink_metadata(to.join("game/data")).unwrap();
+        assert!(metadata.is_dir());
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o777,
+            0o750
+        );
+        assert_eq!(
+            std::fs::read_link(to.join("game/link")).unwrap(),
+            Path::new("data/file")
+        );
+        assert_eq!(
+            std::fs::read_to_string(to.join("game/link")).unwrap(),
+            "contents"
+        );
+    }
+
+    #[test]
+    fn refuses_to_write_through_a_symlinked_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = dir.path().join("outside");
+        std::fs::create_dir(&outside).unwrap();
+
+        let data = testutil::spk(&[testutil::package(
+            "game",
+            vec![
+                Entry::symlink("link", outside.to_str().unwrap()),
+                Entry::file("link/file", b"escaped"),
+            ],
+        )]);
+        let mut file = spk::SPKFile::parse_bytes(data).unwrap();
+        let to = dir.path().join("extracted");
+        let err = extract(&mut file, &to, &ExtractOptions::default()).unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<sanitize::PathError>(),
+            Some(sanitize::PathError::Symlink(_))
+        ));
+        assert!(!outside.join("file").exists());
+        assert!(!to.exists());
+    }
+
+    #[test]
+    fn refuses_unsupported_file_types() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = testutil::spk(&[testutil::package(
+            "game",
+            vec![
+                Entry::file("file", b"contents"),
+                Entry::with_mode("fifo", 0o010_644, b""),
+            ],
+        )]);
+        let mut file = spk::SPKFile::parse_bytes(data).unwrap();
+        let to = dir.path().join("extracted");
+        let err = extract(&mut file, &to, &ExtractOptions::default()).unwrap_err();
+
+        let Some(err) = err.downcast_ref::<spk::UnsupportedFileKind>() else {
+            panic!("expected an unsupported file type, got {err:?}");
+        };
+        assert_eq!(err.name, "fifo");
+        assert_eq!(err.mode, 0o010_644);
+        assert!(!to.exists());
+    }
+
+    /// Two packages in which every file whose name contains `broken` fails verification.
+    fn with_broken_files() -> spk::SPKFile<'static> {
+        let mut data = testutil::spk(&[
+            testutil::package(
+                "game",
+                vec![
+                    Entry::file("a.hex", b"a.hex"),
+                    Entry::file("b.hex", b"b.hex"),
+                    Entry::file("broken.bin", b"broken file"),
+                    Entry::file("c.bin", b"c.bin"),
+                ],
+            ),
+            testutil::package("other", vec![Entry::file("d.hex", b"broken too")]),
+        ]);
+        for contents in [&b"broken file"[..], b"broken too"] {
+            let offset = data
+                .windows(contents.len())
+                .position(|window| window == contents)
+                .unwrap();
+            data[offset] = b'B';
+        }
+        spk::SPKFile::parse_bytes(data).unwrap()
+    }
+
+    fn extracted(to: &Path) -> Vec<String> {
+        let mut files = Vec::new();
+        for package in std::fs::read_dir(to).unwrap() {
+            let package = package.unwrap();
+            for file in std::fs::read_dir(package.path()).unwrap() {
+                files.push(format!(
+                    "{}/{}",
+                    package.file_name().to_str().unwrap(),
+                    file.unwrap().file_name().to_str().unwrap()
+                ));
+            }
+        }
+        files.sort();
+        files
+    }
+
+    fn patterns(patterns: &[&str]) -> Vec<glob::Pattern> {
+        patterns
+            .iter()
+            .map(|pattern| glob::Pattern::new(pattern).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn unselected_files_are_not_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = with_broken_files();
+        let to = dir.path().join("extracted");
+
+        assert!(extract(&mut file, &to, &ExtractOptions::default()).is_err());
+        assert!(!to.exists());
+
+        let options = ExtractOptions {
+            packages: vec!["game".to_string()],
+            exclude: patterns(&["broken*"]),
+            ..ExtractOptions::default()
+        };
+        extract(&mut file, &to, &options).unwrap();
+        assert_eq!(extracted(&to), ["game/a.hex", "game/b.hex", "game/c.bin"]);
+    }
+
+    #[test]
+    fn files_are_selected_by_include_and_exclude_patterns() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = with_broken_files();
+        let to = dir.path().join("extracted");
+
+        let options = ExtractOptions {
+            packages: vec!["game".to_string()],
+            include: patterns(&["*.hex", "c.*"]),
+            exclude: patterns(&["b*"]),
+            ..ExtractOptions::default()
+        };
+        extract(&mut file, &to, &options).unwrap();
+        assert_eq!(extracted(&to), ["game/a.hex", "game/c.bin"]);
+    }
+
+    #[test]
+    fn selections_that_match_nothing_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = with_broken_files();
+        let to = dir.path().join("extracted");
+
+        let options = ExtractOptions {
+            include: patterns(&["*.txt"]),
+            ..ExtractOptions::default()
+        };
+        assert_eq!(
+            extract(&mut file, &to, &options).unwrap_err().to_string(),
+            "No files matched the selected packages and patterns"
+        );
+
+        let options = ExtractOptions {
+            packages: vec!["missing".to_string()],
+            ..ExtractOptions::default()
+        };
+        assert_eq!(
+            extract(&mut file, &to, &options).unwrap_err().to_string(),
+            "No package named missing"
+        );
+        assert!(!to.exists());
+    }
+}
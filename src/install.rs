@@ -0,0 +1,490 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context as _;
+
+use crate::{extract, sanitize, spk, verify};
+
+/// The directory within the target root that holds the state of installs.
+///
+/// Each install is prepared in [`PENDING_DIR`], which holds the staged files,
+/// the backup of the files they replace and the manifest describing both. Once
+/// the install succeeds it replaces [`CURRENT_DIR`], so that a failed install
+/// never destroys the backup of the previous one.
+const STATE_DIR: &str = ".spike-spk-install";
+const PENDING_DIR: &str = "pending";
+const CURRENT_DIR: &str = "current";
+const STAGING_DIR: &str = "staging";
+const BACKUP_DIR: &str = "backup";
+const MANIFEST: &str = "manifest.json";
+
+/// The changes made to the target root by an install, which are undone by [`rollback`].
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct Manifest {
+    /// Files that existed before the install, and whose originals are in the backup.
+    replaced: Vec<PathBuf>,
+    /// Files that did not exist before the install.
+    created: Vec<PathBuf>,
+    /// Directories that did not exist before the install, parents first.
+    created_dirs: Vec<PathBuf>,
+    /// The permissions to give the directories within the update that did not
+    /// exist before the install, once their contents are in place.
+    #[serde(skip)]
+    directory_permissions: Vec<(PathBuf, u32)>,
+}
+
+fn ignore_not_found(result: std::io::Result<()>) -> std::io::Result<()> {
+    match result {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn create_parent(path: &Path) -> anyhow::Result<()> {
+    let parent = path
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get parent directory for {}", path.display()))?;
+    std::fs::create_dir_all(parent)?;
+    Ok(())
+}
+
+/// Write every file within `file` to the staging directory, returning the
+/// manifest of changes that swapping them into `root` will make.
+fn stage(file: &spk::SPKFile, root: &Path, staging: &Path) -> anyhow::Result<Manifest> {
+    let mut manifest = Manifest::default();
+    let mut created_dirs = BTreeSet::new();
+
+    for package in &file.packages {
+        println!(
+            "Staging package {} {}.{}.{}",
+            package.name, package.version.0, package.version.1, package.version.2
+        );
+
+        for file_info in &package.files {
+            let kind = file_info.kind()?;
+            let relative_path = package.install_path(file_info);
+            let target_path = sanitize::resolve(root, &relative_path)?;
+
+            let is_directory = kind == spk::FileKind::Directory;
+            if is_directory {
+                match std::fs::symlink_metadata(&target_path) {
+                    Ok(metadata) if metadata.is_dir() => {}
+                    Ok(_) => anyhow::bail!(
+                        "Cannot install directory {} because a file exists at that path",
+                        target_path.display()
+                    ),
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                        manifest
+                            .directory_permissions
+                            .push((relative_path.clone(), file_info.permissions()));
+                    }
+                    Err(err) => Err(err)?,
+                }
+            }
+
+            // The entry itself if it is a directory, and then its parents.
+            for ancestor in relative_path.ancestors().skip(usize::from(!is_directory)) {
+                if ancestor.as_os_str().is_empty() || root.join(ancestor).exists() {
+                    break;
+                }
+                created_dirs.insert(ancestor.to_path_buf());
+            }
+            if is_directory {
+                continue;
+            }
+
+            match std::fs::symlink_metadata(&target_path) {
+                Ok(metadata) if metadata.is_dir() => anyhow::bail!(
+                    "Cannot install {} because a directory exists at that path",
+                    target_path.display()
+                ),
+                Ok(_) => manifest.replaced.push(relative_path.clone()),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    manifest.created.push(relative_path.clone());
+                }
+                Err(err) => Err(err)?,
+            }
+
+            println!("   {}", file_info.name);
+            let staged_path = staging.join(&relative_path);
+            extract::write_entry(file, file_info, &staged_path)?;
+            if kind == spk::FileKind::Regular {
+                std::fs::File::open(&staged_path)?.sync_all()?;
+            }
+        }
+    }
+
+    // A `BTreeSet` orders each directory before its children.
+    manifest.created_dirs = created_dirs.into_iter().collect();
+    Ok(manifest)
+}
+
+/// Copy the file at `from` to `to`, copying a symlink rather than its target.
+fn copy_entry(from: &Path, to: &Path) -> std::io::Result<()> {
+    if std::fs::symlink_metadata(from)?.is_symlink() {
+        std::os::unix::fs::symlink(std::fs::read_link(from)?, to)
+    } else {
+        std::fs::copy(from, to).map(|_| ())
+    }
+}
+
+/// Preserve the current version of each file that the install will replace.
+fn back_up(root: &Path, backup: &Path, manifest: &Manifest) -> anyhow::Result<()> {
+    for relative_path in &manifest.replaced {
+        let original = root.join(relative_path);
+        let backup_path = backup.join(relative_path);
+        create_parent(&backup_path)?;
+        if std::fs::hard_link(&original, &backup_path).is_err() {
+            copy_entry(&original, &backup_path)
+                .with_context(|| format!("Failed to back up {}", original.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Stage the files within `file` and back up the files they replace, then
+/// record both in the manifest.
+fn prepare(file: &spk::SPKFile, root: &Path, state: &Path) -> anyhow::Result<Manifest> {
+    let manifest = stage(file, root, &state.join(STAGING_DIR))?;
+    back_up(root, &state.join(BACKUP_DIR), &manifest)?;
+    std::fs::write(state.join(MANIFEST), serde_json::to_vec_pretty(&manifest)?)?;
+    Ok(manifest)
+}
+
+/// Move each staged file over its target, replacing the existing file atomically.
+fn swap(root: &Path, staging: &Path, manifest: &Manifest) -> anyhow::Result<()> {
+    for directory in &manifest.created_dirs {
+        std::fs::create_dir_all(root.join(directory))?;
+    }
+
+    for relative_path in manifest.replaced.iter().chain(&manifest.created) {
+        let target_path = root.join(relative_path);
+        std::fs::rename(staging.join(relative_path), &target_path)
+            .with_context(|| format!("Failed to install {}", target_path.display()))?;
+    }
+
+    let directories = manifest
+        .directory_permissions
+        .iter()
+        .map(|(path, permissions)| (root.join(path), *permissions))
+        .collect::<Vec<_>>();
+    extract::set_directory_permissions(&directories)
+}
+
+/// Install `file` into the file tree at `root` the way a machine's updater
+/// does, so that a failed install can be undone with [`rollback`].
+///
+/// Every file is verified, then written to a staging area within `root`. Each
+/// file the install replaces is backed up before the staged files are renamed
+/// into place. If swapping the files in fails, the install is rolled back.
+/// Only the most recent install can be rolled back.
+pub fn install(file: &spk::SPKFile, root: &Path) -> anyhow::Result<()> {
+    if !root.is_dir() {
+        anyhow::bail!("Install target is not a directory: {}", root.display());
+    }
+
+    let pending = root.join(STATE_DIR).join(PENDING_DIR);
+    if pending.join(MANIFEST).exists() {
+        anyhow::bail!(
+            "A previous install into {} did not complete. Roll it back before installing again.",
+            root.display()
+        );
+    }
+
+    sanitize::validate_packages(&file.packages).context("Refusing to install update")?;
+    for package in &file.packages {
+        for file_info in &package.files {
+            let path = package.install_path(file_info);
+            if path.starts_with(STATE_DIR) {
+                anyhow::bail!(
+                    "Refusing to install update: {} is within the install state directory {STATE_DIR}",
+                    path.display()
+                );
+            }
+        }
+    }
+
+    print!("Verifying contents of file...");
+    std::io::Write::flush(&mut std::io::stdout())?;
+    verify::verify_all(file, &file.packages)?;
+    println!(" done!");
+
+    // Without a manifest, nothing in `root` was changed by whatever left this behind.
+    ignore_not_found(std::fs::remove_dir_all(&pending)).with_context(|| {
+        format!(
+            "Failed to remove incomplete install state {}",
+            pending.display()
+        )
+    })?;
+    std::fs::create_dir_all(pending.join(STAGING_DIR))?;
+
+    // Nothing in `root` has changed until the manifest is written, so a
+    // failure before then only needs the pending state cleaned up.
+    let manifest = prepare(file, root, &pending).inspect_err(|_| {
+        let _ = std::fs::remove_dir_all(&pending);
+    })?;
+
+    println!("\n");
+    println!(
+        "Installing {} files",
+        manifest.replaced.len() + manifest.created.len()
+    );
+    commit(root, &manifest)?;
+    println!("done!");
+    Ok(())
+}
+
+/// Swap the prepared install into `root`, then make its state the one that
+/// [`rollback`] undoes. If the swap fails, the install is rolled back and the
+/// state of the previous install is left as it was.
+fn commit(root: &Path, manifest: &Manifest) -> anyhow::Result<()> {
+    let state = root.join(STATE_DIR);
+    let pending = state.join(PENDING_DIR);
+    if let Err(err) = swap(root, &pending.join(STAGING_DIR), manifest) {
+        return Err(match roll_back_state(root, &pending) {
+            Ok(()) => err.context("Install failed and was rolled back"),
+            Err(rollback_err) => err.context(format!(
+                "Install failed and could not be rolled back: {rollback_err:#}"
+            )),
+        });
+    }
+
+    std::fs::remove_dir_all(pending.join(STAGING_DIR))?;
+    let current = state.join(CURRENT_DIR);
+    ignore_not_found(std::fs::remove_dir_all(&current)).with_context(|| {
+        format!(
+            "Failed to remove previous install state {}",
+            current.display()
+        )
+    })?;
+    std::fs::rename(&pending, &current)?;
+    Ok(())
+}
+
+/// Undo the most recent [`install`] into `root`, restoring the files it
+/// replaced and removing the files and directories it created.
+pub fn rollback(root: &Path) -> anyhow::Result<()> {
+    // An install that was interrupted before it was committed is the most recent one.
+    let state = root.join(STATE_DIR);
+    let pending = state.join(PENDING_DIR);
+    if pending.join(MANIFEST).exists() {
+        roll_back_state(root, &pending)
+    } else {
+        roll_back_state(root, &state.join(CURRENT_DIR))
+    }
+}
+
+/// Undo the install described by the manifest in `state`, then remove `state`.
+fn roll_back_state(root: &Path, state: &Path) -> anyhow::Result<()> {
+    let manifest_path = state.join(MANIFEST);
+    let manifest: Manifest = serde_json::from_slice(
+        &std::fs::read(&manifest_path)
+            .with_context(|| format!("No install to roll back in {}", root.display()))?,
+    )
+    .with_context(|| format!("Failed to read {}", manifest_path.display()))?;
+
+    // An install may have been interrupted part way through, so any of these
+    // files may not have been swapped in yet.
+    let backup = state.join(BACKUP_DIR);
+    for relative_path in &manifest.replaced {
+        println!("   restoring {}", relative_path.display());
+        let target_path = root.join(relative_path);
+        std::fs::rename(backup.join(relative_path), &target_path)
+            .with_context(|| format!("Failed to restore {}", target_path.display()))?;
+    }
+    for relative_path in &manifest.created {
+        // Files are only ever installed, so a directory here was not created
+        // by the install and is what stopped the file from being swapped in.
+        let target_path = root.join(relative_path);
+        if target_path
+            .symlink_metadata()
+            .is_ok_and(|metadata| metadata.is_dir())
+        {
+            continue;
+        }
+        println!("   removing {}", relative_path.display());
+        ignore_not_found(std::fs::remove_file(target_path))?;
+    }
+    for directory in manifest.created_dirs.iter().rev() {
+        // Leave behind any directory that has since gained other files.
+        let _ = std::fs::remove_dir(root.join(directory));
+    }
+
+    std::fs::remove_dir_all(state)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        PackageType,
+        testutil::{self, Entry, Package},
+    };
+
+    fn update(entries: Vec<Entry>) -> spk::SPKFile<'static> {
+        spk::SPKFile::parse_bytes(testutil::spk(&[testutil::package("game", entries)])).unwrap()
+    }
+
+    fn read(root: &Path, name: &str) -> Option<String> {
+        std::fs::read_to_string(root.join("games").join(name)).ok()
+    }
+
+    #[test]
+    fn failed_swap_is_rolled_back_and_keeps_the_previous_install() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir(root.join("games")).unwrap();
+        std::fs::write(root.join("games/a"), "original").unwrap();
+
+        install(&update(vec![Entry::file("a", b"first")]), root).unwrap();
+        assert_eq!(read(root, "a").as_deref(), Some("first"));
+
+        let second = update(vec![
+            Entry::file("a", b"second"),
+            Entry::file("b", b"second"),
+            Entry::file("c", b"second"),
+        ]);
+        let pending = root.join(STATE_DIR).join(PENDING_DIR);
+        std::fs::create_dir_all(pending.join(STAGING_DIR)).unwrap();
+        let manifest = prepare(&second, root, &pending).unwrap();
+
+        // `a` and `b` are swapped in before a non-empty directory stops `c`.
+        std::fs::create_dir_all(root.join("games/c/obstacle")).unwrap();
+        let err = commit(root, &manifest).unwrap_err();
+        assert!(format!("{err:#}").starts_with("Install failed and was rolled back"));
+
+        assert_eq!(read(root, "a").as_deref(), Some("first"));
+        assert_eq!(read(root, "b"), None);
+        assert!(!pending.exists());
+        assert!(
+            root.join(STATE_DIR)
+                .join(CURRENT_DIR)
+                .join(MANIFEST)
+                .exists()
+        );
+
+        // The first install can still be rolled back.
+        rollback(root).unwrap();
+        assert_eq!(read(root, "a").as_deref(), Some("original"));
+        assert!(rollback(root).is_err());
+    }
+
+    #[test]
+    fn successful_install_replaces_the_previous_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+
+        install(&update(vec![Entry::file("a", b"first")]), root).unwrap();
+        install(
+            &update(vec![
+                Entry::file("a", b"second"),
+                Entry::file("b", b"second"),
+            ]),
+            root,
+        )
+        .unwrap();
+        assert_eq!(read(root, "a").as_deref(), Some("second"));
+        assert!(!root.join(STATE_DIR).join(PENDING_DIR).exists());
+
+        rollback(root).unwrap();
+        assert_eq!(read(root, "a").as_deref(), Some("first"));
+        assert_eq!(read(root, "b"), None);
+    }
+
+    #[test]
+    fn interrupted_install_is_rolled_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir(root.join("games")).unwrap();
+        std::fs::write(root.join("games/a"), "original").unwrap();
+
+        let update = update(vec![
+            Entry::file("a", b"new"),
+            Entry::file("b", b"new"),
+            Entry::file("c", b"new"),
+        ]);
+        let pending = root.join(STATE_DIR).join(PENDING_DIR);
+        std::fs::create_dir_all(pending.join(STAGING_DIR)).unwrap();
+        let manifest = prepare(&update, root, &pending).unwrap();
+
+        // Stop part way through the swap without rolling back, as if the
+        // process had been killed.
+        std::fs::create_dir_all(root.join("games/c/obstacle")).unwrap();
+        swap(root, &pending.join(STAGING_DIR), &manifest).unwrap_err();
+        assert_eq!(read(root, "a").as_deref(), Some("new"));
+        assert_eq!(read(root, "b").as_deref(), Some("new"));
+
+        let err = install(&update, root).unwrap_err();
+        assert!(err.to_string().contains("did not complete"));
+
+        rollback(root).unwrap();
+        assert_eq!(read(root, "a").as_deref(), Some("original"));
+        assert_eq!(read(root, "b"), None);
+        assert!(root.join("games/c/obstacle").is_dir());
+        assert!(!pending.exists());
+    }
+
+    #[test]
+    fn replaced_symlinks_are_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir(root.join("games")).unwrap();
+        std::fs::write(root.join("games/target"), "target").unwrap();
+        std::os::unix::fs::symlink("target", root.join("games/a")).unwrap();
+
+        install(&update(vec![Entry::file("a", b"file")]), root).unwrap();
+        assert!(!root.join("games/a").is_symlink());
+        assert_eq!(read(root, "a").as_deref(), Some("file"));
+        assert_eq!(read(root, "target").as_deref(), Some("target"));
+
+        rollback(root).unwrap();
+        assert_eq!(
+            std::fs::read_link(root.join("games/a")).unwrap(),
+            Path::new("target")
+        );
+        assert_eq!(read(root, "target").as_deref(), Some("target"));
+    }
+
+    #[test]
+    fn copied_backups_of_symlinks_are_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("target"), "target").unwrap();
+        std::os::unix::fs::symlink("target", dir.path().join("link")).unwrap();
+
+        copy_entry(&dir.path().join("link"), &dir.path().join("backup")).unwrap();
+        assert_eq!(
+            std::fs::read_link(dir.path().join("backup")).unwrap(),
+            Path::new("target")
+        );
+    }
+
+    #[test]
+    fn files_within_the_state_directory_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        install(&update(vec![Entry::file("a", b"first")]), root).unwrap();
+
+        let package = Package {
+            type_: PackageType::Spike2,
+            ..testutil::package(
+                "spike",
+                vec![Entry::file(
+                    ".spike-spk-install/current/manifest.json",
+                    b"{}",
+                )],
+            )
+        };
+        let file = spk::SPKFile::parse_bytes(testutil::spk(&[package])).unwrap();
+        let err = install(&file, root).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Refusing to install update: .spike-spk-install/current/manifest.json is within the install state directory .spike-spk-install"
+        );
+
+        rollback(root).unwrap();
+        assert_eq!(read(root, "a"), None);
+    }
+}
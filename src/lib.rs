@@ -1,7 +1,17 @@
+pub mod diff;
+pub mod export;
 pub mod extract;
+pub mod install;
+pub mod overlay;
+pub mod raw;
+pub mod sanitize;
 pub mod spk;
 pub mod verify;
+pub mod write;
+pub use chunks::PackageType;
 pub use spk::SPKFile;
 
 mod chunks;
 mod squashed;
+#[cfg(test)]
+mod testutil;
@@ -0,0 +1,278 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context as _;
+
+use crate::{extract, sanitize, spk, verify};
+
+/// Something noteworthy that happened while applying updates, which did not
+/// prevent the remaining files from being applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// A file was overwritten by a different package than the one that
+    /// previously wrote it during this overlay.
+    Conflict {
+        path: PathBuf,
+        previous_package: String,
+        package: String,
+    },
+    /// A package was applied with a lower version than an earlier update in the chain.
+    VersionRegression {
+        package: String,
+        previous_version: (u8, u8, u8),
+        version: (u8, u8, u8),
+    },
+    /// A file was not applied because a directory exists at its path.
+    DirectoryInTheWay { path: PathBuf, package: String },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let version = |v: &(u8, u8, u8)| format!("{}.{}.{}", v.0, v.1, v.2);
+        match self {
+            Warning::Conflict {
+                path,
+                previous_package,
+                package,
+            } => write!(
+                f,
+                "{} from package {package} replaced the file from package {previous_package}",
+                path.display()
+            ),
+            Warning::VersionRegression {
+                package,
+                previous_version,
+                version: new_version,
+            } => write!(
+                f,
+                "Package {package} went from version {} to older version {}",
+                version(previous_version),
+                version(new_version)
+            ),
+            Warning::DirectoryInTheWay { path, package } => write!(
+                f,
+                "Skipped {} from package {package} because a directory exists at that path",
+                path.display()
+            ),
+        }
+    }
+}
+
+/// Apply each of `updates` in order on top of the file tree rooted at `base`,
+/// reproducing the files a machine has after installing them.
+///
+/// Each package's files are placed beneath `base` according to
+/// [`crate::PackageType::path_prefix`], replacing any existing file at the
+/// same path. Every update is verified before any files are written.
+pub fn overlay(base: &Path, updates: &[spk::SPKFile]) -> anyhow::Result<Vec<Warning>> {
+    for (i, update) in updates.iter().enumerate() {
+        sanitize::validate_packages(&update.packages)
+            .with_context(|| format!("Refusing to apply update {}", i + 1))?;
+        for file_info in update.packages.iter().flat_map(|package| &package.files) {
+            file_info
+                .kind()
+                .with_context(|| format!("Refusing to apply update {}", i + 1))?;
+        }
+        verify::verify_all(update, &update.packages)
+            .with_context(|| format!("Failed to verify update {}", i + 1))?;
+    }
+
+    let mut warnings = Vec::new();
+    let mut versions = HashMap::<&str, (u8, u8, u8)>::new();
+    let mut written_by = HashMap::<PathBuf, &str>::new();
+    let mut directories = Vec::new();
+
+    for update in updates {
+        for package in &update.packages {
+            if let Some(&previous_version) = versions.get(package.name.as_str())
+                && package.version < previous_version
+            {
+                warnings.push(Warning::VersionRegression {
+                    package: package.name.clone(),
+                    previous_version,
+                    version: package.version,
+                });
+            }
+            versions.insert(&package.name, package.version);
+
+            println!(
+                "Applying package {} {}.{}.{}",
+                package.name, package.version.0, package.version.1, package.version.2
+            );
+
+            for file_info in &package.files {
+                let relative_path = package.install_path(file_info);
+                let output_path = sanitize::resolve(base, &relative_path)?;
+                let is_directory = file_info.kind()? == spk::FileKind::Directory;
+
+                match std::fs::symlink_metadata(&output_path) {
+                    Ok(metadata) if metadata.is_dir() && is_directory => {}
+                    Ok(metadata) if metadata.is_dir() => {
+                        warnings.push(Warning::DirectoryInTheWay {
+                            path: relative_path,
+                            package: package.name.clone(),
+                        });
+                        continue;
+                    }
+                    Ok(_) => std::fs::remove_file(&output_path).with_context(|| {
+                        format!("Failed to remove existing {}", output_path.display())
+                    })?,
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(err) => Err(err)?,
+                }
+
+                if !is_directory
+                    && let Some(previous_package) =
+                        written_by.insert(relative_path.clone(), &package.name)
+                    && previous_package != package.name
+                {
+                    warnings.push(Warning::Conflict {
+                        path: relative_path,
+                        previous_package: previous_package.to_string(),
+                        package: package.name.clone(),
+                    });
+                }
+
+                println!("   {}", file_info.name);
+                extract::write_entry(update, file_info, &output_path)?;
+                if is_directory {
+                    directories.push((output_path, file_info.permissions()));
+                }
+            }
+        }
+    }
+
+    extract::set_directory_permissions(&directories)?;
+    Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, Entry, Package};
+
+    fn update(packages: &[Package]) -> spk::SPKFile<'static> {
+        spk::SPKFile::parse_bytes(testutil::spk(packages)).unwrap()
+    }
+
+    fn versioned(name: &str, version: (u8, u8, u8), entries: Vec<Entry>) -> Package {
+        Package {
+            version,
+            ..testutil::package(name, entries)
+        }
+    }
+
+    #[test]
+    fn the_last_update_wins() {
+        let base = tempfile::tempdir().unwrap();
+        std::fs::create_dir(base.path().join("games")).unwrap();
+        std::fs::write(base.path().join("games/a"), b"base").unwrap();
+
+        let updates = [
+            update(&[versioned(
+                "game",
+                (1, 14, 0),
+                vec![Entry::file("a", b"first"), Entry::file("b", b"first")],
+            )]),
+            update(&[versioned(
+                "game",
+                (1, 15, 0),
+                vec![Entry::file("a", b"second")],
+            )]),
+        ];
+
+        assert_eq!(overlay(base.path(), &updates).unwrap(), []);
+        let read = |name| std::fs::read_to_string(base.path().join("games").join(name)).unwrap();
+        assert_eq!(read("a"), "second");
+        assert_eq!(read("b"), "first");
+    }
+
+    #[test]
+    fn files_replaced_by_another_package_are_conflicts() {
+        let base = tempfile::tempdir().unwrap();
+        let updates = [
+            update(&[testutil::package(
+                "first",
+                vec![Entry::file("shared", b"first")],
+            )]),
+            update(&[testutil::package(
+                "second",
+                vec![Entry::file("shared", b"second")],
+            )]),
+        ];
+
+        assert_eq!(
+            overlay(base.path(), &updates).unwrap(),
+            [Warning::Conflict {
+                path: PathBuf::from("games/shared"),
+                previous_package: "first".to_string(),
+                package: "second".to_string(),
+            }]
+        );
+        assert_eq!(
+            std::fs::read_to_string(base.path().join("games/shared")).unwrap(),
+            "second"
+        );
+    }
+
+    #[test]
+    fn older_versions_are_version_regressions() {
+        let base = tempfile::tempdir().unwrap();
+        let updates = [
+            update(&[versioned(
+                "game",
+                (1, 15, 0),
+                vec![Entry::file("a", b"new")],
+            )]),
+            update(&[versioned(
+                "game",
+                (1, 14, 0),
+                vec![Entry::file("a", b"old")],
+            )]),
+        ];
+
+        let warnings = overlay(base.path(), &updates).unwrap();
+        assert_eq!(
+            warnings,
+            [Warning::VersionRegression {
+                package: "game".to_string(),
+                previous_version: (1, 15, 0),
+                version: (1, 14, 0),
+            }]
+        );
+        assert_eq!(
+            warnings[0].to_string(),
+            "Package game went from version 1.15.0 to older version 1.14.0"
+        );
+        assert_eq!(
+            std::fs::read_to_string(base.path().join("games/a")).unwrap(),
+            "old"
+        );
+    }
+
+    #[test]
+    fn files_are_not_written_over_directories() {
+        let base = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(base.path().join("games/a/child")).unwrap();
+        let updates = [update(&[testutil::package(
+            "game",
+            vec![Entry::file("a", b"file"), Entry::file("b", b"file")],
+        )])];
+
+        assert_eq!(
+            overlay(base.path(), &updates).unwrap(),
+            [Warning::DirectoryInTheWay {
+                path: PathBuf::from("games/a"),
+                package: "game".to_string(),
+            }]
+        );
+        assert!(base.path().join("games/a/child").is_dir());
+        assert_eq!(
+            std::fs::read_to_string(base.path().join("games/b")).unwrap(),
+            "file"
+        );
+    }
+}
@@ -0,0 +1,576 @@
+//! Low-level access to the chunks that make up a SPK file, for tools that need
+//! to look at the file's structure rather than the packages it contains.
+
+use binrw::{BinRead, PosValue};
+use md5::digest::generic_array::GenericArray;
+
+use crate::{
+    PackageType, chunks,
+    spk::{self, ParseMode},
+};
+
+/// The magic numbers of the chunk types that are recognized.
+const KNOWN_MAGIC: [&[u8; 4]; 9] = [
+    b"SPKS", b"SPK0", b"SIDX", b"SZ64", b"STRS", b"FINF", b"FI64", b"FEND", b"SDAT",
+];
+
+/// How the length of a chunk is stored in its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderForm {
+    /// A 32-bit length following the magic number, for an 8 byte header.
+    /// Chunks that can only have a 32-bit length always use this form.
+    Old,
+    /// A `0xffffffff` marker followed by a 64-bit length, for a 16 byte header.
+    New,
+}
+
+impl HeaderForm {
+    fn of(byte_len: chunks::ByteLen) -> Self {
+        match byte_len {
+            chunks::ByteLen::Old(_) => HeaderForm::Old,
+            chunks::ByteLen::New(_) => HeaderForm::New,
+        }
+    }
+
+    /// The size of the header, including the magic number.
+    #[must_use]
+    pub fn header_size(&self) -> u64 {
+        match self {
+            HeaderForm::Old => 8,
+            HeaderForm::New => 16,
+        }
+    }
+}
+
+/// A chunk within a SPK file, along with the chunks nested within it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub magic: [u8; 4],
+    /// The absolute offset of the chunk's magic number within the SPK file.
+    pub offset: u64,
+    pub header_form: HeaderForm,
+    /// The length of the chunk following its header, as recorded in the header.
+    pub payload_len: u64,
+    pub data: ChunkData,
+    /// A `SPKS` chunk contains each `SPK0` chunk, and a `SPK0` chunk contains
+    /// the chunks describing its package. Other chunks have no children.
+    pub children: Vec<Chunk>,
+}
+
+/// The fields of a chunk, as parsed from its payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ChunkData {
+    SPKS {
+        chunk_count: u32,
+    },
+    SPK0,
+    SIDX {
+        /// The package's name, padded with NUL bytes.
+        package_name: [u8; 0x20],
+        version: (u8, u8, u8),
+        package_type: PackageType,
+        /// The trailing bytes of the chunk. Their meaning is unknown.
+        unknown: [u8; 0xc],
+    },
+    /// A chunk found in some packages between `SIDX` and `STRS`. Its meaning is unknown.
+    SZ64 {
+        unknown: u64,
+    },
+    /// The NUL terminated names of the package's files.
+    STRS {
+        string_data: Vec<u8>,
+    },
+    FINF(FileInfoChunk),
+    FI64(FileInfoChunk),
+    FEND,
+    /// The data of the package's files, which starts after the chunk's header.
+    SDAT,
+    /// A chunk of a type that is not recognized, which was skipped in
+    /// [`ParseMode::Lenient`]. Its payload is not read.
+    Unknown,
+}
+
+/// The fields of a `FINF` or `FI64` chunk. The fields of a `FINF` chunk are
+/// widened to match those of a `FI64` chunk.
+#[derive(Clone, PartialEq, Eq)]
+pub struct FileInfoChunk {
+    /// The name found at `filename_offset`.
+    pub filename: String,
+    /// Relative to the start of the `STRS` chunk's string data.
+    pub filename_offset: u64,
+    pub file_size: u64,
+    /// Relative to the end of the `SDAT` chunk's header.
+    pub data_offset: u64,
+    pub data_size: u64,
+    pub mode: u16,
+    pub pad_before_hmac: [u8; 3],
+    pub hmac: [u8; 20],
+    pub md5: [u8; 16],
+    /// 3 bytes for a `FINF` chunk and 7 bytes for a `FI64` chunk.
+    pub pad_after_md5: Vec<u8>,
+}
+
+impl std::fmt::Debug for FileInfoChunk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileInfoChunk")
+            .field("filename", &self.filename)
+            .field("filename_offset", &self.filename_offset)
+            .field("file_size", &self.file_size)
+            .field("data_offset", &self.data_offset)
+            .field("data_size", &self.data_size)
+            .field("mode", &self.mode)
+            .field("pad_before_hmac", &self.pad_before_hmac)
+            .field(
+                "hmac",
+                &format_args!("{:02x}", GenericArray::from(self.hmac)),
+            )
+            .field("md5", &format_args!("{:02x}", GenericArray::from(self.md5)))
+            .field("pad_after_md5", &self.pad_after_md5)
+            .finish()
+    }
+}
+
+impl Chunk {
+    fn new(
+        magic: [u8; 4],
+        offset: u64,
+        header_form: HeaderForm,
+        payload_len: u64,
+        data: ChunkData,
+    ) -> Self {
+        Self {
+            magic,
+            offset,
+            header_form,
+            payload_len,
+            data,
+            children: Vec::new(),
+        }
+    }
+
+    /// The chunk's magic number as text, such as `SPK0`.
+    #[must_use]
+    pub fn name(&self) -> String {
+        String::from_utf8_lossy(&self.magic).into_owned()
+    }
+
+    #[must_use]
+    pub fn header_size(&self) -> u64 {
+        self.header_form.header_size()
+    }
+
+    /// The absolute offset of the byte following the chunk's header.
+    #[must_use]
+    pub fn payload_offset(&self) -> u64 {
+        self.offset.saturating_add(self.header_size())
+    }
+
+    /// The absolute offset of the byte following the chunk's payload.
+    #[must_use]
+    pub fn end(&self) -> u64 {
+        self.payload_offset().saturating_add(self.payload_len)
+    }
+
+    /// Visit this chunk and every chunk nested within it, depth first and in
+    /// the order they appear in the file. Each chunk is paired with its depth,
+    /// which is 0 for this chunk.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Chunk)> {
+        let mut stack = vec![(0, self)];
+        std::iter::from_fn(move || {
+            let (depth, chunk) = stack.pop()?;
+            stack.extend(chunk.children.iter().rev().map(|child| (depth + 1, child)));
+            Some((depth, chunk))
+        })
+    }
+}
+
+fn file_info_chunk(pos: u64, file_info: chunks::FileInfo) -> Chunk {
+    match file_info {
+        chunks::FileInfo::FINF(finf) => Chunk::new(
+            *b"FINF",
+            pos,
+            HeaderForm::Old,
+            u64::from(finf.byte_len),
+            ChunkData::FINF(FileInfoChunk {
+                filename: finf.filename.to_string(),
+                filename_offset: u64::from(finf.filename_offset),
+                file_size: u64::from(finf.file_size),
+                data_offset: u64::from(finf.data_offset),
+                data_size: u64::from(finf.data_size),
+                mode: finf.mode,
+                pad_before_hmac: finf.pad_before_hmac,
+                hmac: finf.data_hmac,
+                md5: finf.data_md5,
+                pad_after_md5: finf.pad_after_md5.to_vec(),
+            }),
+        ),
+        chunks::FileInfo::FI64(fi64) => Chunk::new(
+            *b"FI64",
+            pos,
+            HeaderForm::Old,
+            u64::from(fi64.byte_len),
+            ChunkData::FI64(FileInfoChunk {
+                filename: fi64.filename.to_string(),
+                filename_offset: fi64.filename_offset,
+                file_size: fi64.file_size,
+                data_offset: fi64.data_offset,
+                data_size: fi64.data_size,
+                mode: fi64.mode,
+                pad_before_hmac: fi64.pad_before_hmac,
+                hmac: fi64.data_hmac,
+                md5: fi64.data_md5,
+                pad_after_md5: fi64.pad_after_md5.to_vec(),
+            }),
+        ),
+        chunks::FileInfo::FEND(_) => Chunk::new(*b"FEND", pos, HeaderForm::Old, 0, ChunkData::FEND),
+    }
+}
+
+/// Return the offset of the chunk at the current position along with its
+/// magic number, or `None` at the end of the file, without consuming it.
+fn peek_magic<R>(reader: &mut R) -> std::io::Result<(u64, Option<[u8; 4]>)>
+where
+    R: std::io::Read + std::io::Seek,
+{
+    let offset = reader.stream_position()?;
+    let mut magic = [0; 4];
+    let magic = match reader.read_exact(&mut magic) {
+        Ok(()) => Some(magic),
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => None,
+        Err(err) => return Err(err),
+    };
+    reader.seek(std::io::SeekFrom::Start(offset))?;
+    Ok((offset, magic))
+}
+
+/// Read the chunk at the current position, which must start with one of the
+/// magic numbers in `magic`. `expected` names them for error messages.
+fn read_chunk<T, R>(
+    reader: &mut R,
+    package: Option<usize>,
+    expected: &'static str,
+    magic: &[&[u8; 4]],
+    args: T::Args<'_>,
+) -> Result<PosValue<T>, spk::OpenError>
+where
+    T: BinRead,
+    R: std::io::Read + std::io::Seek,
+{
+    let (offset, found) = peek_magic(reader)?;
+    let Some(found) = found else {
+        Err(spk::ChunkError::UnexpectedEnd {
+            package,
+            offset,
+            expected,
+        })?
+    };
+    if !magic.contains(&&found) {
+        Err(spk::ChunkError::UnexpectedMagic {
+            package,
+            offset,
+            expected,
+            found,
+        })?;
+    }
+
+    Ok(
+        PosValue::<T>::read_le_args(reader, args).map_err(|error| spk::ChunkError::Malformed {
+            package,
+            offset,
+            magic: found,
+            error,
+        })?,
+    )
+}
+
+/// In [`ParseMode::Lenient`], skip past any chunks at the current position
+/// whose type is not recognized, adding them to `chunks`.
+fn skip_unknown<R>(
+    reader: &mut R,
+    mode: ParseMode,
+    package: Option<usize>,
+    chunks: &mut Vec<Chunk>,
+) -> Result<(), spk::OpenError>
+where
+    R: std::io::Read + std::io::Seek,
+{
+    if mode == ParseMode::Strict {
+        return Ok(());
+    }
+
+    loop {
+        // Reaching the end of the file is reported by the next chunk that is read.
+        let (offset, Some(magic)) = peek_magic(reader)? else {
+            return Ok(());
+        };
+        if KNOWN_MAGIC.contains(&&magic) {
+            return Ok(());
+        }
+
+        reader.seek(std::io::SeekFrom::Current(4))?;
+        let byte_len =
+            chunks::ByteLen::read_le(reader).map_err(|error| spk::ChunkError::Malformed {
+                package,
+                offset,
+                magic,
+                error,
+            })?;
+        let chunk = Chunk::new(
+            magic,
+            offset,
+            HeaderForm::of(byte_len),
+            byte_len.byte_len(),
+            ChunkData::Unknown,
+        );
+        reader.seek(std::io::SeekFrom::Start(chunk.end()))?;
+        chunks.push(chunk);
+    }
+}
+
+/// Read the chunks describing the package numbered `index`, starting from its
+/// `SPK0` chunk, and add them to `packages`. The package is added as soon as
+/// its `SPK0` chunk is read, so that it holds the chunks read before any error.
+fn read_package<R>(
+    reader: &mut R,
+    mode: ParseMode,
+    index: usize,
+    packages: &mut Vec<Chunk>,
+) -> Result<(), spk::OpenError>
+where
+    R: std::io::Read + std::io::Seek,
+{
+    let package_index = Some(index);
+    let spk0 = read_chunk::<chunks::SPK0, _>(reader, package_index, "SPK0", &[b"SPK0"], ())?;
+    packages.push(Chunk::new(
+        *b"SPK0",
+        spk0.pos,
+        HeaderForm::of(spk0.byte_len),
+        spk0.byte_len.byte_len(),
+        ChunkData::SPK0,
+    ));
+    let package = packages.last_mut().expect("the package was just added");
+
+    skip_unknown(reader, mode, package_index, &mut package.children)?;
+    let sidx = read_chunk::<chunks::SIDX, _>(reader, package_index, "SIDX", &[b"SIDX"], ())?;
+    package.children.push(Chunk::new(
+        *b"SIDX",
+        sidx.pos,
+        HeaderForm::of(sidx.byte_len),
+        sidx.byte_len.byte_len(),
+        ChunkData::SIDX {
+            package_name: sidx.package_name,
+            version: (sidx.major_version, sidx.minor_version, sidx.patch_version),
+            package_type: sidx.package_type,
+            unknown: sidx.unknown_b,
+        },
+    ));
+
+    skip_unknown(reader, mode, package_index, &mut package.children)?;
+    let has_sz64 = peek_magic(reader)?.1 == Some(*b"SZ64");
+    if has_sz64 {
+        let sz64 = read_chunk::<chunks::SZ64, _>(reader, package_index, "SZ64", &[b"SZ64"], ())?;
+        package.children.push(Chunk::new(
+            *b"SZ64",
+            sz64.pos,
+            HeaderForm::Old,
+            u64::from(sz64.byte_len),
+            ChunkData::SZ64 {
+                unknown: sz64.unknown,
+            },
+        ));
+    }
+
+    skip_unknown(reader, mode, package_index, &mut package.children)?;
+    let expected = if has_sz64 { "STRS" } else { "SZ64 or STRS" };
+    let strs = read_chunk::<chunks::STRS, _>(reader, package_index, expected, &[b"STRS"], ())?;
+    let strs_offset = strs.pos + 8;
+    package.children.push(Chunk::new(
+        *b"STRS",
+        strs.pos,
+        HeaderForm::Old,
+        u64::from(strs.byte_len),
+        ChunkData::STRS {
+            string_data: strs.val.string_data,
+        },
+    ));
+
+    loop {
+        skip_unknown(reader, mode, package_index, &mut package.children)?;
+        // Read the type of chunk that is present, so that an error describes that type.
+        let chunk = match peek_magic(reader)?.1.as_ref() {
+            Some(b"FI64") => {
+                let fi64 = read_chunk::<chunks::FI64, _>(
+                    reader,
+                    package_index,
+                    "FI64",
+                    &[b"FI64"],
+                    (strs_offset,),
+                )?;
+                file_info_chunk(fi64.pos, chunks::FileInfo::FI64(fi64.val))
+            }
+            Some(b"FEND") => {
+                let fend =
+                    read_chunk::<chunks::FEND, _>(reader, package_index, "FEND", &[b"FEND"], ())?;
+                file_info_chunk(fend.pos, chunks::FileInfo::FEND(fend.val))
+            }
+            _ => {
+                let finf = read_chunk::<chunks::FINF, _>(
+                    reader,
+                    package_index,
+                    "FINF, FI64 or FEND",
+                    &[b"FINF"],
+                    (strs_offset,),
+                )?;
+                file_info_chunk(finf.pos, chunks::FileInfo::FINF(finf.val))
+            }
+        };
+        let is_end = chunk.data == ChunkData::FEND;
+        package.children.push(chunk);
+        if is_end {
+            break;
+        }
+    }
+
+    skip_unknown(reader, mode, package_index, &mut package.children)?;
+    let sdat = read_chunk::<chunks::SDAT, _>(reader, package_index, "SDAT", &[b"SDAT"], ())?;
+    package.children.push(Chunk::new(
+        *b"SDAT",
+        sdat.pos,
+        HeaderForm::of(sdat.byte_len),
+        sdat.byte_len.byte_len(),
+        ChunkData::SDAT,
+    ));
+
+    Ok(())
+}
+
+/// Read the tree of chunks making up a SPK file from the start of `reader`.
+///
+/// The contents of the `SDAT` chunks are not read. See [`spk::SPKFile::chunks`]
+/// to read the chunks of a file that has already been opened.
+///
+/// In [`ParseMode::Lenient`], chunks of an unrecognized type are skipped and
+/// included in the tree as [`ChunkData::Unknown`].
+pub fn read_chunks<R>(reader: R, mode: ParseMode) -> Result<Chunk, spk::OpenError>
+where
+    R: std::io::Read + std::io::Seek,
+{
+    read_chunks_partial(reader, mode).map_err(|partial| partial.error)
+}
+
+/// The chunks that [`read_chunks_partial`] read before it failed.
+#[derive(Debug)]
+pub struct PartialChunks {
+    /// The tree read so far, or `None` if not even the `SPKS` chunk could be read.
+    pub root: Option<Chunk>,
+    pub error: spk::OpenError,
+}
+
+/// Like [`read_chunks`], but on failure also returns the chunks that were read
+/// before the error, so that a damaged file can be examined up to that point.
+pub fn read_chunks_partial<R>(mut reader: R, mode: ParseMode) -> Result<Chunk, Box<PartialChunks>>
+where
+    R: std::io::Read + std::io::Seek,
+{
+    let spks = reader
+        .seek(std::io::SeekFrom::Start(0))
+        .map_err(spk::OpenError::from)
+        .and_then(|_| read_chunk::<chunks::SPKS, _>(&mut reader, None, "SPKS", &[b"SPKS"], ()))
+        .map_err(|error| Box::new(PartialChunks { root: None, error }))?;
+    let mut root = Chunk::new(
+        *b"SPKS",
+        spks.pos,
+        HeaderForm::of(spks.byte_length),
+        spks.byte_length.byte_len(),
+        ChunkData::SPKS {
+            chunk_count: spks.chunk_count,
+        },
+    );
+
+    match read_packages(&mut reader, mode, spks.chunk_count, &mut root.children) {
+        Ok(()) => Ok(root),
+        Err(error) => Err(Box::new(PartialChunks {
+            root: Some(root),
+            error,
+        })),
+    }
+}
+
+fn read_packages<R>(
+    reader: &mut R,
+    mode: ParseMode,
+    chunk_count: u32,
+    children: &mut Vec<Chunk>,
+) -> Result<(), spk::OpenError>
+where
+    R: std::io::Read + std::io::Seek,
+{
+    for index in 0..chunk_count as usize {
+        skip_unknown(reader, mode, None, children)?;
+        read_package(reader, mode, index, children)?;
+
+        // The next SPK0 starts at the end of this one.
+        let end = children.last().map_or(0, Chunk::end);
+        reader.seek(std::io::SeekFrom::Start(end))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, Entry};
+
+    fn names(chunks: &[Chunk]) -> Vec<String> {
+        chunks.iter().map(Chunk::name).collect()
+    }
+
+    #[test]
+    fn partial_tree_holds_the_chunks_read_before_an_error() {
+        let mut data = testutil::spk(&[
+            testutil::package("first", vec![Entry::file("a", b"a")]),
+            testutil::package("second", vec![Entry::file("b", b"b")]),
+        ]);
+        let second_sidx = data
+            .windows(4)
+            .enumerate()
+            .filter(|(_, window)| *window == b"SIDX")
+            .nth(1)
+            .unwrap()
+            .0;
+        data[second_sidx + 3] = b'Y';
+
+        let partial =
+            read_chunks_partial(std::io::Cursor::new(data), ParseMode::Strict).unwrap_err();
+        assert!(matches!(
+            partial.error,
+            spk::OpenError::Chunk(spk::ChunkError::UnexpectedMagic {
+                package: Some(1),
+                offset,
+                expected: "SIDX",
+                found: [b'S', b'I', b'D', b'Y'],
+            }) if offset == second_sidx as u64
+        ));
+
+        let root = partial.root.unwrap();
+        assert_eq!(names(&root.children), ["SPK0", "SPK0"]);
+        assert_eq!(
+            names(&root.children[0].children),
+            ["SIDX", "STRS", "FINF", "FEND", "SDAT"]
+        );
+        assert!(root.children[1].children.is_empty());
+    }
+
+    #[test]
+    fn no_tree_without_a_spks_chunk() {
+        let partial =
+            read_chunks_partial(std::io::Cursor::new(b"garbage".to_vec()), ParseMode::Strict)
+                .unwrap_err();
+        assert!(partial.root.is_none());
+        assert!(matches!(
+            partial.error,
+            spk::OpenError::Chunk(spk::ChunkError::UnexpectedMagic { offset: 0, .. })
+        ));
+    }
+}
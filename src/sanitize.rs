@@ -0,0 +1,328 @@
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use crate::spk;
+
+/// Why a file name from a SPK file cannot safely be written beneath a directory.
+#[derive(Error, Debug)]
+pub enum PathError {
+    #[error("File name is empty")]
+    Empty,
+    #[error("File name contains a NUL byte: {0:?}")]
+    ContainsNul(String),
+    #[error("File name is absolute: {0}")]
+    Absolute(String),
+    #[error("File name contains a `..` component: {0}")]
+    ParentDir(String),
+    #[error("File name contains an empty or `.` component: {0}")]
+    EmptyComponent(String),
+    #[error("Package name {0:?} is not a valid directory name: {1}")]
+    PackageName(String, #[source] Box<PathError>),
+    #[error("More than one file is installed at {0}")]
+    Duplicate(PathBuf),
+    #[error("File names differ only by case: {0} and {1}")]
+    CaseCollision(PathBuf, PathBuf),
+    #[error("Path passes through a symbolic link: {0}")]
+    Symlink(PathBuf),
+    #[error("Failed to inspect {0}: {1}")]
+    IOError(PathBuf, std::io::Error),
+}
+
+/// Check that `name` is a relative path made up only of normal components, so
+/// that joining it to a directory cannot produce a path outside of it.
+pub fn validate_name(name: &str) -> Result<(), PathError> {
+    if name.is_empty() {
+        return Err(PathError::Empty);
+    }
+    if name.contains('\0') {
+        return Err(PathError::ContainsNul(name.to_string()));
+    }
+    if name.starts_with('/') {
+        return Err(PathError::Absolute(name.to_string()));
+    }
+
+    // `Path::components` silently drops empty and `.` components, so split manually.
+    for component in name.split('/') {
+        match component {
+            "" | "." => return Err(PathError::EmptyComponent(name.to_string())),
+            ".." => return Err(PathError::ParentDir(name.to_string())),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Check that no two of `paths`, or their parent directories, would refer to
+/// the same file on a case-insensitive file system. Identical paths are allowed.
+pub fn check_case_collisions<'a>(
+    paths: impl IntoIterator<Item = &'a Path>,
+) -> Result<(), PathError> {
+    let mut seen = HashMap::<String, &Path>::new();
+    for path in paths {
+        for ancestor in path.ancestors() {
+            if ancestor.as_os_str().is_empty() {
+                break;
+            }
+
+            let folded = ancestor.to_string_lossy().to_lowercase();
+            match seen.get(&folded) {
+                Some(&other) if other != ancestor => {
+                    return Err(PathError::CaseCollision(
+                        other.to_path_buf(),
+                        ancestor.to_path_buf(),
+                    ));
+                }
+                Some(_) => {}
+                None => {
+                    seen.insert(folded, ancestor);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check every package name and file name within `packages`, and check that
+/// no two files other than directories are installed at the same path, even on
+/// a case-insensitive file system.
+///
+/// Package names are checked too as they name the directory each package is
+/// extracted to.
+pub fn validate_packages(packages: &[spk::Package]) -> Result<(), PathError> {
+    for package in packages {
+        validate_name(&package.name)
+            .map_err(|err| PathError::PackageName(package.name.clone(), Box::new(err)))?;
+        for file in &package.files {
+            validate_name(&file.name)?;
+        }
+    }
+
+    // Whether each path is a directory. A later entry at the same path would
+    // otherwise be written over, or through, an earlier one.
+    let mut install_paths = HashMap::<PathBuf, bool>::new();
+    for package in packages {
+        for file in &package.files {
+            let path = package.install_path(file);
+            let is_directory = matches!(file.kind(), Ok(spk::FileKind::Directory));
+            match install_paths.insert(path.clone(), is_directory) {
+                Some(true) if is_directory => {}
+                Some(_) => return Err(PathError::Duplicate(path)),
+                None => {}
+            }
+        }
+    }
+    check_case_collisions(install_paths.keys().map(PathBuf::as_path))
+}
+
+/// Join `relative` to `root`, checking that none of the directories between
+/// them that already exist is a symbolic link that could redirect the write
+/// elsewhere. The final component may be a symbolic link, as callers replace
+/// it rather than writing through it.
+pub fn resolve(root: &Path, relative: &Path) -> Result<PathBuf, PathError> {
+    let components = relative
+        .components()
+        .map(|component| match component {
+            Component::Normal(component) => Ok(component),
+            Component::ParentDir => Err(PathError::ParentDir(
+                relative.to_string_lossy().into_owned(),
+            )),
+            Component::RootDir | Component::Prefix(_) => {
+                Err(PathError::Absolute(relative.to_string_lossy().into_owned()))
+            }
+            Component::CurDir => Err(PathError::EmptyComponent(
+                relative.to_string_lossy().into_owned(),
+            )),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let Some((file_name, directories)) = components.split_last() else {
+        return Err(PathError::Empty);
+    };
+
+    let mut path = root.to_path_buf();
+    let mut exists = true;
+    for directory in directories {
+        path.push(directory);
+        if !exists {
+            continue;
+        }
+
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                return Err(PathError::Symlink(path));
+            }
+            Ok(_) => {}
+            // Nothing beneath a missing directory can be a symbolic link.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => exists = false,
+            Err(err) => return Err(PathError::IOError(path, err)),
+        }
+    }
+    path.push(file_name);
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, Entry};
+
+    /// Parse a crafted SPK file with a package per name, each holding a file per path.
+    fn packages(packages: &[(&str, &[&str])]) -> Vec<spk::Package> {
+        let packages = packages
+            .iter()
+            .map(|(name, paths)| {
+                let entries = paths.iter().map(|path| Entry::file(path, b"x")).collect();
+                testutil::package(name, entries)
+            })
+            .collect::<Vec<_>>();
+        spk::SPKFile::parse_bytes(testutil::spk(&packages))
+            .unwrap()
+            .packages
+    }
+
+    fn validate(packages: &[(&str, &[&str])]) -> Result<(), PathError> {
+        validate_packages(&self::packages(packages))
+    }
+
+    #[test]
+    fn accepts_normal_names() {
+        validate(&[("game", &["a", "dir/b", "dir/sub/c"]), ("spike", &["d"])]).unwrap();
+    }
+
+    #[test]
+    fn rejects_parent_components() {
+        for name in ["..", "../etc/passwd", "dir/../../etc/passwd", "dir/.."] {
+            assert!(
+                matches!(validate(&[("game", &[name])]), Err(PathError::ParentDir(_))),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_empty_components() {
+        for name in ["dir//file", "dir/", "./file", "dir/./file"] {
+            assert!(
+                matches!(
+                    validate(&[("game", &[name])]),
+                    Err(PathError::EmptyComponent(_))
+                ),
+                "{name}"
+            );
+        }
+        assert!(matches!(
+            validate(&[("game", &[""])]),
+            Err(PathError::Empty)
+        ));
+    }
+
+    #[test]
+    fn rejects_absolute_names() {
+        assert!(matches!(
+            validate(&[("game", &["/etc/passwd"])]),
+            Err(PathError::Absolute(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_nul_bytes() {
+        // A name read from a string table ends at the first NUL.
+        assert!(matches!(
+            validate_name("file\0.txt"),
+            Err(PathError::ContainsNul(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_package_names() {
+        for name in ["..", "", "/tmp", "a//b"] {
+            assert!(
+                matches!(
+                    validate(&[(name, &["file"])]),
+                    Err(PathError::PackageName(package, _)) if package == name
+                ),
+                "{name:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_case_collisions() {
+        assert!(matches!(
+            validate(&[("game", &["File", "file"])]),
+            Err(PathError::CaseCollision(_, _))
+        ));
+        // Collisions between parent directories and across packages count too.
+        assert!(matches!(
+            validate(&[("game", &["Dir/a"]), ("other", &["dir/b"])]),
+            Err(PathError::CaseCollision(_, _))
+        ));
+    }
+
+    #[test]
+    fn rejects_duplicate_install_paths() {
+        let validate_entries = |entries: Vec<Vec<Entry>>| {
+            let packages = entries
+                .into_iter()
+                .enumerate()
+                .map(|(i, entries)| testutil::package(&format!("package{i}"), entries))
+                .collect::<Vec<_>>();
+            validate_packages(
+                &spk::SPKFile::parse_bytes(testutil::spk(&packages))
+                    .unwrap()
+                    .packages,
+            )
+        };
+
+        assert!(matches!(
+            validate_entries(vec![vec![
+                Entry::symlink("link", "/etc"),
+                Entry::file("link", b"x"),
+            ]]),
+            Err(PathError::Duplicate(path)) if path == Path::new("games/link")
+        ));
+        assert!(matches!(
+            validate_entries(vec![
+                vec![Entry::file("file", b"x")],
+                vec![Entry::file("file", b"y")],
+            ]),
+            Err(PathError::Duplicate(_))
+        ));
+        assert!(matches!(
+            validate_entries(vec![vec![
+                Entry::directory("dir"),
+                Entry::file("dir", b"x")
+            ]]),
+            Err(PathError::Duplicate(_))
+        ));
+        validate_entries(vec![
+            vec![Entry::directory("dir"), Entry::file("dir/a", b"x")],
+            vec![Entry::directory("dir"), Entry::file("dir/b", b"y")],
+        ])
+        .unwrap();
+    }
+
+    #[test]
+    fn resolve_rejects_symlinked_parents() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("real")).unwrap();
+        std::os::unix::fs::symlink(dir.path(), root.join("link")).unwrap();
+
+        assert_eq!(
+            resolve(&root, Path::new("real/missing/file")).unwrap(),
+            root.join("real/missing/file")
+        );
+        assert!(matches!(
+            resolve(&root, Path::new("link/file")),
+            Err(PathError::Symlink(path)) if path == root.join("link")
+        ));
+        assert!(matches!(
+            resolve(&root, Path::new("../file")),
+            Err(PathError::ParentDir(_))
+        ));
+    }
+}
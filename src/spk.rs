@@ -1,15 +1,15 @@
 use std::{
+    borrow::Cow,
     ffi::OsStr,
-    io::Cursor,
-    path::Path,
+    io::Read as _,
+    path::{Path, PathBuf},
     result::Result,
-    sync::{Arc, Mutex},
+    sync::Mutex,
 };
 
-use binrw::{BinRead, PosValue};
 use thiserror::Error;
 
-use crate::{chunks, squashed};
+use crate::{chunks, raw, squashed};
 
 pub(crate) const HMAC_KEY: &[u8] = &[
     0x8e, 0x1f, 0x55, 0x43, 0xc2, 0xf5, 0x4a, 0x11, 0x67, 0x3a, 0x28, 0x2a, 0x2f, 0x87, 0xc0, 0x06,
@@ -19,8 +19,13 @@ pub(crate) const HMAC_KEY: &[u8] = &[
 pub enum OpenError {
     #[error("Failed to read file: {0}")]
     IOError(#[from] std::io::Error),
+    /// No longer returned, as chunks that cannot be parsed are reported as
+    /// [`OpenError::Chunk`] with their package and offset. Kept so that existing
+    /// matches still compile.
     #[error("Failed to parse file: {0}")]
-    Parse(#[from] binrw::Error),
+    Parse(binrw::Error),
+    #[error("Failed to parse file: {0}")]
+    Chunk(#[from] ChunkError),
     #[error("File name contained invalid UTF-8: {0}")]
     Utf8(#[from] std::str::Utf8Error),
     #[error("Failed to read SquashFS file: {0}")]
@@ -33,36 +38,315 @@ pub enum OpenError {
     DirectoryDoesNotContainSplitSPK,
 }
 
+impl OpenError {
+    /// Whether the error was caused by a failure to read the file rather than by its contents.
+    #[must_use]
+    pub fn is_io(&self) -> bool {
+        match self {
+            OpenError::IOError(_) => true,
+            OpenError::SquashFS(err) => err.is_io(),
+            OpenError::Parse(err) => matches!(err, binrw::Error::Io(_)),
+            OpenError::Chunk(err) => err.is_io(),
+            _ => false,
+        }
+    }
+}
+
+fn in_package(package: Option<usize>) -> String {
+    package.map_or_else(String::new, |package| format!(" in package {package}"))
+}
+
+/// Why the chunks making up a SPK file could not be parsed. Packages are
+/// numbered from 0 in the order they appear in the file.
+#[derive(Error, Debug)]
+pub enum ChunkError {
+    #[error(
+        "Expected {expected} chunk{} at offset {offset:#x}, found \"{}\"",
+        in_package(*.package),
+        .found.escape_ascii()
+    )]
+    UnexpectedMagic {
+        /// The package being parsed, or `None` outside of any package.
+        package: Option<usize>,
+        /// The absolute offset at which the chunk was expected.
+        offset: u64,
+        /// The magic numbers of the chunks that could appear at `offset`, such as `FINF, FI64 or FEND`.
+        expected: &'static str,
+        found: [u8; 4],
+    },
+    #[error(
+        "Expected {expected} chunk{} at offset {offset:#x}, found the end of the file",
+        in_package(*.package)
+    )]
+    UnexpectedEnd {
+        package: Option<usize>,
+        offset: u64,
+        expected: &'static str,
+    },
+    #[error(
+        "Failed to parse {} chunk{} at offset {offset:#x}: {}",
+        .magic.escape_ascii(),
+        in_package(*.package),
+        .error.root_cause()
+    )]
+    Malformed {
+        package: Option<usize>,
+        offset: u64,
+        magic: [u8; 4],
+        /// Includes a backtrace of the fields that were being parsed.
+        error: binrw::Error,
+    },
+    #[error("Package {package} has no {expected} chunk")]
+    Missing {
+        package: usize,
+        expected: &'static str,
+    },
+}
+
+impl ChunkError {
+    /// Whether the chunk could not be parsed because the file could not be read.
+    #[must_use]
+    pub fn is_io(&self) -> bool {
+        match self {
+            ChunkError::Malformed { error, .. } => matches!(
+                error.root_cause(),
+                binrw::Error::Io(err) if err.kind() != std::io::ErrorKind::UnexpectedEof
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// How to treat chunks of a type this crate does not recognize.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Fail to parse the file.
+    #[default]
+    Strict,
+    /// Skip the chunk using the length in its header, and record it in
+    /// [`SPKFile::unknown_chunks`].
+    Lenient,
+}
+
+/// A chunk of an unrecognized type that was skipped while parsing in
+/// [`ParseMode::Lenient`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownChunk {
+    pub magic: [u8; 4],
+    /// The absolute offset of the chunk's magic number within the SPK file.
+    pub offset: u64,
+    /// The length of the chunk following its header.
+    pub payload_len: u64,
+}
+
+impl std::fmt::Display for UnknownChunk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Skipped unknown chunk {:?} at offset {:#x} ({} bytes)",
+            String::from_utf8_lossy(&self.magic),
+            self.offset,
+            self.payload_len
+        )
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum ReadError {
     #[error("Failed to read file: {0}")]
     IOError(#[from] std::io::Error),
+    #[error("File is too large to read into memory: {0} bytes")]
+    TooLarge(u64),
+    /// No longer returned, as file data is not parsed. Kept so that existing
+    /// matches still compile.
     #[error("Failed to parse file: {0}")]
-    Parse(#[from] binrw::Error),
+    Parse(binrw::Error),
+    #[error(
+        "File {name} stores {data_size} bytes for {file_size} bytes of content in an unsupported encoding (data begins {magic:02x?})"
+    )]
+    UnsupportedEncoding {
+        name: String,
+        file_size: u64,
+        data_size: u64,
+        magic: Vec<u8>,
+    },
+}
+
+#[derive(Error, Debug)]
+#[error("File {name} has an unsupported type: mode {mode:o}")]
+pub struct UnsupportedFileKind {
+    pub name: String,
+    pub mode: u16,
+}
+
+/// The type of a file, as given by the `S_IFMT` bits of its mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Regular,
+    Directory,
+    /// A symbolic link, whose data is the path it points to.
+    Symlink,
+}
+
+/// Fails if the reader it wraps does not produce exactly the expected number of bytes.
+struct ExactLength<R> {
+    inner: R,
+    name: String,
+    expected: u64,
+    read: u64,
+}
+
+impl<R: std::io::Read> std::io::Read for ExactLength<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.read += read as u64;
+        let message = if self.read > self.expected {
+            format!("is longer than {} bytes", self.expected)
+        } else if read == 0 && !buf.is_empty() && self.read < self.expected {
+            format!("ended after {} of {} bytes", self.read, self.expected)
+        } else {
+            return Ok(read);
+        };
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("File {} {message}", self.name),
+        ))
+    }
+}
+
+/// Positional reads from the underlying data of a SPK file.
+///
+/// Unlike `Read` + `Seek`, reads do not share a cursor and so can be issued
+/// concurrently from multiple threads.
+pub(crate) trait ReadAt: Send + Sync {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize>;
+    fn size(&self) -> std::io::Result<u64>;
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for Box<T> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn size(&self) -> std::io::Result<u64> {
+        (**self).size()
+    }
+}
+
+impl ReadAt for std::fs::File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buf, offset)
+    }
+
+    fn size(&self) -> std::io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+impl ReadAt for Cow<'_, [u8]> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let data = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| self.get(offset..))
+            .unwrap_or_default();
+        let len = buf.len().min(data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn size(&self) -> std::io::Result<u64> {
+        Ok(<[u8]>::len(self) as u64)
+    }
 }
 
-trait SeekableReader: std::io::Read + std::io::Seek + Send {}
-impl<T> SeekableReader for T where T: std::io::Read + std::io::Seek + Send {}
+/// Fallback for generic readers, which serializes reads on a single cursor.
+impl<R> ReadAt for Mutex<R>
+where
+    R: std::io::Read + std::io::Seek + Send,
+{
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let mut reader = self
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        reader.seek(std::io::SeekFrom::Start(offset))?;
+        reader.read(buf)
+    }
+
+    fn size(&self) -> std::io::Result<u64> {
+        self.lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .seek(std::io::SeekFrom::End(0))
+    }
+}
 
 pub struct SPKFile<'a> {
+    pub source: Source,
     pub packages: Vec<Package>,
-    reader: Arc<Mutex<dyn SeekableReader + 'a>>,
+    /// The chunks that were skipped because their type is not recognized.
+    /// Always empty unless the file was opened in [`ParseMode::Lenient`].
+    pub unknown_chunks: Vec<UnknownChunk>,
+    mode: ParseMode,
+    reader: Box<dyn ReadAt + 'a>,
 }
 
 impl std::fmt::Debug for SPKFile<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("File")
+            .field("source", &self.source)
             .field("packages", &self.packages)
+            .field("unknown_chunks", &self.unknown_chunks)
             .finish()
     }
 }
 
+/// Where a [`SPKFile`] was read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// A single .spk file.
+    SingleFile(PathBuf),
+    /// A .spk file stored within a `SquashFS` image that is split across `pieces`.
+    SplitSquashed {
+        pieces: Vec<PathBuf>,
+        /// The path of the .spk file within the `SquashFS` image.
+        inner_path: PathBuf,
+        /// The size of the .spk file within the `SquashFS` image.
+        inner_size: u64,
+    },
+    /// A reader or in-memory data passed to [`SPKFile::parse`] or [`SPKFile::parse_bytes`].
+    Reader,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Package {
     pub name: String,
     pub version: (u8, u8, u8),
     pub type_: chunks::PackageType,
     pub files: Vec<FileInfo>,
+    /// The value of the package's `SZ64` chunk, if it has one. Its meaning is unknown.
+    pub sz64: Option<u64>,
+    /// The trailing bytes of the package's `SIDX` chunk. Their meaning is unknown.
+    pub sidx_unknown: [u8; 0xc],
+}
+
+impl Package {
+    /// The total size of the files within the package.
+    #[must_use]
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|file| file.size).sum()
+    }
+
+    /// Whether any of the package's files is described by a `FI64` rather than `FINF` chunk.
+    #[must_use]
+    pub fn uses_fi64(&self) -> bool {
+        self.files.iter().any(|file| file.fi64)
+    }
+
+    /// The path at which `file` is installed on a machine, relative to its root
+    /// directory.
+    #[must_use]
+    pub fn install_path(&self, file: &FileInfo) -> PathBuf {
+        Path::new(self.type_.path_prefix().trim_start_matches('/')).join(&file.name)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -74,70 +358,294 @@ pub struct FileInfo {
     pub hmac: [u8; 20],
     pub md5: [u8; 16],
     pub mode: u16,
+    /// Whether the file is described by a `FI64` rather than `FINF` chunk.
+    pub(crate) fi64: bool,
+}
+
+impl FileInfo {
+    /// The absolute offset of the file's data within the SPK file.
+    #[must_use]
+    pub fn data_offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The number of bytes of data stored for the file.
+    #[must_use]
+    pub fn data_size(&self) -> u64 {
+        self.data_size
+    }
+
+    /// The type of the file. A mode without any type bits set is treated as a
+    /// regular file.
+    pub fn kind(&self) -> Result<FileKind, UnsupportedFileKind> {
+        match self.mode & 0o170_000 {
+            0o100_000 | 0 => Ok(FileKind::Regular),
+            0o040_000 => Ok(FileKind::Directory),
+            0o120_000 => Ok(FileKind::Symlink),
+            _ => Err(UnsupportedFileKind {
+                name: self.name.clone(),
+                mode: self.mode,
+            }),
+        }
+    }
+
+    /// The permission bits of the file's mode, including setuid, setgid and sticky.
+    #[must_use]
+    pub fn permissions(&self) -> u32 {
+        u32::from(self.mode & 0o7777)
+    }
 }
 
 impl<'a> SPKFile<'a> {
-    pub fn parse<R>(mut reader: R) -> Result<Self, OpenError>
+    /// Parse a SPK file from a generic reader.
+    ///
+    /// Reads of file data are serialized on `reader`. Prefer [`SPKFile::open`] or
+    /// [`SPKFile::parse_bytes`], which allow files to be read concurrently.
+    pub fn parse<R>(reader: R) -> Result<Self, OpenError>
     where
         R: std::io::Read + std::io::Seek + Send + 'a,
     {
-        let spks = chunks::SPKS::read_le(&mut reader)?;
-
-        let mut packages = Vec::new();
-        for _ in 0..spks.chunk_count {
-            let spk0 = PosValue::<chunks::SPK0>::read_le(&mut reader)?;
-            let sidx = chunks::SIDX::read_le(&mut reader)?;
-
-            // TODO: It's unclear what this is used for.
-            let _ = chunks::SZ64::read_le(&mut reader);
-
-            let strs = PosValue::<chunks::STRS>::read_le(&mut reader)?;
-            let mut files = Vec::new();
-            loop {
-                let file_info =
-                    PosValue::<chunks::FileInfo>::read_le_args(&mut reader, (strs.pos + 8,))?;
-                if let chunks::FileInfo::FEND(_) = file_info.val {
-                    break;
-                }
+        Self::from_read_at(Mutex::new(reader), Source::Reader, ParseMode::Strict)
+    }
 
-                let file_info: chunks::FI64 = file_info.val.try_into().unwrap();
-                files.push(FileInfo {
-                    name: file_info.filename.to_string(),
-                    size: file_info.file_size,
-                    offset: file_info.data_offset,
-                    data_size: file_info.data_size,
-                    mode: file_info.mode,
-                    hmac: file_info.data_hmac,
-                    md5: file_info.data_md5,
-                });
-            }
+    /// Parse a SPK file that is held in memory.
+    pub fn parse_bytes<B>(data: B) -> Result<Self, OpenError>
+    where
+        B: Into<Cow<'a, [u8]>>,
+    {
+        Self::from_read_at(data.into(), Source::Reader, ParseMode::Strict)
+    }
+
+    fn from_read_at<R: ReadAt + 'a>(
+        reader: R,
+        source: Source,
+        mode: ParseMode,
+    ) -> Result<Self, OpenError> {
+        let len = reader.size()?;
+        let root = raw::read_chunks(
+            std::io::BufReader::new(FileReader {
+                reader: &reader,
+                start: 0,
+                len,
+                pos: 0,
+            }),
+            mode,
+        )?;
+
+        let packages = root
+            .children
+            .iter()
+            .filter(|chunk| chunk.data == raw::ChunkData::SPK0)
+            .enumerate()
+            .map(|(index, spk0)| Self::parse_package(index, spk0))
+            .collect::<Result<_, _>>()?;
+        let unknown_chunks = root
+            .iter()
+            .filter(|(_, chunk)| chunk.data == raw::ChunkData::Unknown)
+            .map(|(_, chunk)| UnknownChunk {
+                magic: chunk.magic,
+                offset: chunk.offset,
+                payload_len: chunk.payload_len,
+            })
+            .collect();
 
-            let sdat = PosValue::<chunks::SDAT>::read_le(&mut reader)?;
-            for file in &mut files {
-                file.offset += sdat.pos + sdat.header_size();
+        Ok(Self {
+            source,
+            packages,
+            unknown_chunks,
+            mode,
+            reader: Box::new(reader),
+        })
+    }
+
+    /// Build a package from the chunks nested within its `SPK0` chunk.
+    fn parse_package(index: usize, spk0: &raw::Chunk) -> Result<Package, OpenError> {
+        let mut package = None;
+        let mut sz64 = None;
+        let mut files = Vec::new();
+        for chunk in &spk0.children {
+            match &chunk.data {
+                raw::ChunkData::SIDX {
+                    package_name,
+                    version,
+                    package_type,
+                    unknown,
+                } => {
+                    let name = std::str::from_utf8(package_name)?
+                        .trim_end_matches('\0')
+                        .to_string();
+                    package = Some((name, *version, *package_type, *unknown));
+                }
+                // TODO: It's unclear what this is used for.
+                raw::ChunkData::SZ64 { unknown } => sz64 = Some(*unknown),
+                raw::ChunkData::FINF(file_info) | raw::ChunkData::FI64(file_info) => {
+                    files.push(FileInfo {
+                        name: file_info.filename.clone(),
+                        size: file_info.file_size,
+                        offset: file_info.data_offset,
+                        data_size: file_info.data_size,
+                        mode: file_info.mode,
+                        hmac: file_info.hmac,
+                        md5: file_info.md5,
+                        fi64: matches!(chunk.data, raw::ChunkData::FI64(_)),
+                    });
+                }
+                raw::ChunkData::SDAT => {
+                    for file in &mut files {
+                        file.offset = file.offset.saturating_add(chunk.payload_offset());
+                    }
+                }
+                _ => {}
             }
+        }
 
-            let package = Package {
-                name: std::str::from_utf8(&sidx.package_name)?
-                    .trim_end_matches('\0')
-                    .to_string(),
-                version: (sidx.major_version, sidx.minor_version, sidx.patch_version),
-                type_: sidx.package_type,
-                files,
-            };
-            packages.push(package);
+        let (name, version, type_, sidx_unknown) = package.ok_or(ChunkError::Missing {
+            package: index,
+            expected: "SIDX",
+        })?;
+        Ok(Package {
+            name,
+            version,
+            type_,
+            files,
+            sz64,
+            sidx_unknown,
+        })
+    }
+
+    /// Read the tree of chunks making up the file, for tools that need to look
+    /// at its structure. Chunks are read in the mode the file was opened with.
+    /// See [`raw::read_chunks`].
+    pub fn chunks(&self) -> Result<raw::Chunk, OpenError> {
+        raw::read_chunks(
+            std::io::BufReader::new(FileReader {
+                reader: &*self.reader,
+                start: 0,
+                len: self.reader.size()?,
+                pos: 0,
+            }),
+            self.mode,
+        )
+    }
+
+    pub fn open(path: &Path) -> Result<Self, OpenError> {
+        Self::open_with_mode(path, ParseMode::Strict)
+    }
+
+    /// Open the SPK file at `path`, choosing how to treat chunks of a type
+    /// that is not recognized.
+    pub fn open_with_mode(path: &Path, mode: ParseMode) -> Result<Self, OpenError> {
+        let file = UnparsedFile::open(path)?;
+        Self::from_read_at(file.reader, file.source, mode)
+    }
+
+    pub fn open_single_file(path: &Path) -> Result<Self, OpenError> {
+        let file = UnparsedFile::open_single_file(path)?;
+        Self::from_read_at(file.reader, file.source, ParseMode::Strict)
+    }
+
+    pub fn open_split_squashed(path: &Path) -> Result<Self, OpenError> {
+        let file = UnparsedFile::open_split_squashed(path)?;
+        Self::from_read_at(file.reader, file.source, ParseMode::Strict)
+    }
+
+    /// Find the files named `path`, which may be either a file's name within its
+    /// package or its full path on the machine, such as `/games/<name>`.
+    pub fn find_files<'s>(
+        &'s self,
+        path: &'s str,
+    ) -> impl Iterator<Item = (&'s Package, &'s FileInfo)> + 's {
+        self.packages.iter().flat_map(move |package| {
+            let prefix = package.type_.path_prefix();
+            package
+                .files
+                .iter()
+                .filter(move |file| {
+                    file.name == path
+                        || path
+                            .strip_prefix(prefix)
+                            .is_some_and(|name| name == file.name)
+                })
+                .map(move |file| (package, file))
+        })
+    }
+
+    /// Read the contents of `file` into memory.
+    pub fn read(&self, file: &FileInfo) -> Result<Vec<u8>, ReadError> {
+        usize::try_from(file.size).map_err(|_| ReadError::TooLarge(file.size))?;
+        // The sizes come from the file, so only reserve what it could hold.
+        let available = self.reader.size()?.saturating_sub(file.offset);
+        let mut buf =
+            Vec::with_capacity(usize::try_from(file.size.min(available)).unwrap_or_default());
+        self.contents_reader(file)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
 
-            // The next SPK0 starts at `offset`.
-            let offset = spk0.pos + spk0.offset_to_next();
-            reader.seek(std::io::SeekFrom::Start(offset))?;
+    /// Return a reader over the data stored for `file`, without reading it into
+    /// memory. The data is not checked; see [`SPKFile::contents_reader`].
+    #[must_use]
+    pub fn reader(&self, file: &FileInfo) -> FileReader<'_, 'a> {
+        FileReader {
+            reader: &*self.reader,
+            start: file.offset,
+            len: file.data_size,
+            pos: 0,
         }
+    }
 
-        Ok(Self {
-            packages,
-            reader: Arc::new(Mutex::new(reader)),
+    /// Check that the data stored for `file` is its contents.
+    ///
+    /// Only raw data has been seen in updates. Data whose size differs from the
+    /// file's size is in an encoding that is not supported, and is reported with
+    /// its first bytes to help identify the encoding rather than guessed at.
+    pub fn check_encoding(&self, file: &FileInfo) -> Result<(), ReadError> {
+        if file.data_size == file.size {
+            return Ok(());
+        }
+
+        let mut magic = Vec::with_capacity(8);
+        self.reader(file).take(8).read_to_end(&mut magic)?;
+        Err(ReadError::UnsupportedEncoding {
+            name: file.name.clone(),
+            file_size: file.size,
+            data_size: file.data_size,
+            magic,
         })
     }
 
+    /// Return a reader over the contents of `file`, failing if its data is in an
+    /// unsupported encoding. Reading fails if the data ends before `file.size` bytes.
+    pub fn contents_reader(
+        &self,
+        file: &FileInfo,
+    ) -> Result<impl std::io::Read + use<'_, 'a>, ReadError> {
+        self.check_encoding(file)?;
+        Ok(ExactLength {
+            inner: self.reader(file),
+            name: file.name.clone(),
+            expected: file.size,
+            read: 0,
+        })
+    }
+
+    /// Copy the contents of `file` to `writer` and return the number of bytes copied.
+    pub fn copy_to<W>(&self, file: &FileInfo, writer: &mut W) -> Result<u64, ReadError>
+    where
+        W: std::io::Write + ?Sized,
+    {
+        Ok(std::io::copy(&mut self.contents_reader(file)?, writer)?)
+    }
+}
+
+/// A SPK file that has been opened but not parsed, so that its chunks can be
+/// read with [`raw::read_chunks`] even when it cannot be opened as a [`SPKFile`].
+pub struct UnparsedFile {
+    pub source: Source,
+    reader: Box<dyn ReadAt>,
+}
+
+impl UnparsedFile {
+    /// Open the SPK file at `path`, which may be any of the paths accepted by [`SPKFile::open`].
     pub fn open(path: &Path) -> Result<Self, OpenError> {
         if std::fs::metadata(path)?.is_dir() {
             let paths = glob::glob(&format!("{}/*.000", path.display()))?;
@@ -155,23 +663,284 @@ impl<'a> SPKFile<'a> {
         }
     }
 
-    pub fn open_single_file(path: &Path) -> Result<Self, OpenError> {
-        let file = std::fs::File::open(path)?;
-        let reader = Box::new(file);
-        Self::parse(reader)
+    fn open_single_file(path: &Path) -> Result<Self, OpenError> {
+        Ok(Self {
+            source: Source::SingleFile(path.to_path_buf()),
+            reader: Box::new(std::fs::File::open(path)?),
+        })
     }
 
-    pub fn open_split_squashed(path: &Path) -> Result<Self, OpenError> {
-        let spk_file_data = squashed::extract_spk_file(path)?;
-        Self::parse(Cursor::new(spk_file_data))
+    fn open_split_squashed(path: &Path) -> Result<Self, OpenError> {
+        let reader = squashed::open_spk_file(path)?;
+        Ok(Self {
+            source: Source::SplitSquashed {
+                pieces: reader.pieces().to_vec(),
+                inner_path: reader.inner_path().to_path_buf(),
+                inner_size: reader.len(),
+            },
+            reader: Box::new(reader),
+        })
     }
 
-    #[allow(clippy::cast_possible_truncation)]
-    pub fn read(&self, file: &FileInfo) -> Result<Vec<u8>, ReadError> {
-        let mut buf = vec![0; file.data_size as usize];
-        let mut reader = self.reader.lock().unwrap();
-        reader.seek(std::io::SeekFrom::Start(file.offset))?;
-        reader.read_exact(&mut buf)?;
+    /// Return a reader over the whole file, from which to read its chunks.
+    pub fn reader(&self) -> Result<impl std::io::Read + std::io::Seek + '_, OpenError> {
+        Ok(std::io::BufReader::new(FileReader {
+            reader: &*self.reader,
+            start: 0,
+            len: self.reader.size()?,
+            pos: 0,
+        }))
+    }
+}
+
+/// A reader limited to the data of a single file within a [`SPKFile`].
+pub struct FileReader<'s, 'a> {
+    reader: &'s (dyn ReadAt + 'a),
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl std::io::Read for FileReader<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.pos);
+        let len =
+            usize::try_from(remaining).map_or(buf.len(), |remaining| remaining.min(buf.len()));
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let read = self
+            .reader
+            .read_at(&mut buf[..len], self.start.saturating_add(self.pos))?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl std::io::Seek for FileReader<'_, '_> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            std::io::SeekFrom::Start(pos) => Some(pos),
+            std::io::SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+            std::io::SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+        };
+        self.pos = pos.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, Entry};
+
+    fn exact_length(data: &[u8], expected: u64) -> std::io::Result<Vec<u8>> {
+        let mut reader = ExactLength {
+            inner: data,
+            name: "file".to_string(),
+            expected,
+            read: 0,
+        };
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf)?;
         Ok(buf)
     }
+
+    #[test]
+    fn exact_length_accepts_the_expected_length() {
+        assert_eq!(exact_length(b"12345", 5).unwrap(), b"12345");
+        assert_eq!(exact_length(b"", 0).unwrap(), b"");
+    }
+
+    #[test]
+    fn exact_length_rejects_short_data() {
+        let err = exact_length(b"1234", 5).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "File file ended after 4 of 5 bytes");
+    }
+
+    #[test]
+    fn exact_length_rejects_long_data() {
+        let err = exact_length(b"123456", 5).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "File file is longer than 5 bytes");
+    }
+
+    #[test]
+    fn raw_data_is_read_as_is() {
+        let data = testutil::spk(&[testutil::package(
+            "game",
+            vec![Entry::file("a", b"contents"), Entry::file("b", b"")],
+        )]);
+        let file = SPKFile::parse_bytes(data).unwrap();
+        let [a, b] = &file.packages[0].files[..] else {
+            panic!("expected two files");
+        };
+
+        file.check_encoding(a).unwrap();
+        assert_eq!(file.read(a).unwrap(), b"contents");
+        assert_eq!(file.read(b).unwrap(), b"");
+        let mut copied = Vec::new();
+        assert_eq!(file.copy_to(a, &mut copied).unwrap(), 8);
+        assert_eq!(copied, b"contents");
+    }
+
+    #[test]
+    fn data_of_another_size_is_an_unsupported_encoding() {
+        // A gzip stream, which is not guessed at.
+        let mut entry = Entry::file("a", &[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 3, 1, 2]);
+        entry.size = 100;
+        let data = testutil::spk(&[testutil::package("game", vec![entry])]);
+        let file = SPKFile::parse_bytes(data).unwrap();
+        let a = &file.packages[0].files[0];
+
+        for result in [
+            file.check_encoding(a),
+            file.read(a).map(|_| ()),
+            file.copy_to(a, &mut std::io::sink()).map(|_| ()),
+        ] {
+            let Err(ReadError::UnsupportedEncoding {
+                name,
+                file_size,
+                data_size,
+                magic,
+            }) = result
+            else {
+                panic!("expected an unsupported encoding, got {result:?}");
+            };
+            assert_eq!(name, "a");
+            assert_eq!((file_size, data_size), (100, 12));
+            assert_eq!(magic, [0x1f, 0x8b, 8, 0, 0, 0, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn truncated_data_fails_to_read() {
+        let mut data = testutil::spk(&[testutil::package(
+            "game",
+            vec![Entry::file("a", b"contents")],
+        )]);
+        data.truncate(data.len() - 3);
+        let file = SPKFile::parse_bytes(data).unwrap();
+        let a = &file.packages[0].files[0];
+
+        let Err(ReadError::IOError(err)) = file.read(a) else {
+            panic!("expected a short read");
+        };
+        assert_eq!(err.to_string(), "File a ended after 5 of 8 bytes");
+    }
+
+    #[test]
+    fn chunk_errors_give_the_package_chunk_and_offset() {
+        let mut data = testutil::spk(&[testutil::package("game", Vec::new())]);
+        assert_eq!(&data[0x14..0x18], b"SIDX");
+        data[0x17] = b'Y';
+
+        let err = SPKFile::parse_bytes(data).unwrap_err();
+        let OpenError::Chunk(ChunkError::UnexpectedMagic {
+            package,
+            offset,
+            expected,
+            found,
+        }) = &err
+        else {
+            panic!("expected an unexpected chunk");
+        };
+        assert_eq!(*package, Some(0));
+        assert_eq!(*offset, 0x14);
+        assert_eq!(*expected, "SIDX");
+        assert_eq!(found, b"SIDY");
+        assert_eq!(
+            err.to_string(),
+            "Failed to parse file: Expected SIDX chunk in package 0 at offset 0x14, found \"SIDY\""
+        );
+    }
+
+    /// A package whose `SIDX` is followed by an unrecognized chunk in each header form.
+    fn with_unknown_chunks() -> Vec<u8> {
+        let mut package = testutil::package("game", vec![Entry::file("a", b"contents")]);
+        package.extra_chunks.extend(b"XTRA\x04\0\0\0data");
+        package
+            .extra_chunks
+            .extend(b"NEWX\xff\xff\xff\xff\x02\0\0\0\0\0\0\0hi");
+        testutil::spk(&[package])
+    }
+
+    #[test]
+    fn strict_mode_rejects_unknown_chunks() {
+        let Err(OpenError::Chunk(ChunkError::UnexpectedMagic {
+            package,
+            offset,
+            expected,
+            found,
+        })) = SPKFile::parse_bytes(with_unknown_chunks())
+        else {
+            panic!("expected an unexpected chunk");
+        };
+        assert_eq!(package, Some(0));
+        assert_eq!(offset, 0x4c);
+        assert_eq!(expected, "SZ64 or STRS");
+        assert_eq!(&found, b"XTRA");
+    }
+
+    #[test]
+    fn lenient_mode_skips_and_reports_unknown_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("unknown.spk");
+        std::fs::write(&path, with_unknown_chunks()).unwrap();
+
+        assert!(SPKFile::open(&path).is_err());
+        let file = SPKFile::open_with_mode(&path, ParseMode::Lenient).unwrap();
+        let warnings = file
+            .unknown_chunks
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            warnings,
+            [
+                "Skipped unknown chunk \"XTRA\" at offset 0x4c (4 bytes)",
+                "Skipped unknown chunk \"NEWX\" at offset 0x58 (2 bytes)",
+            ]
+        );
+
+        // The rest of the package is parsed as if the chunks were not there.
+        let package = &file.packages[0];
+        assert_eq!(package.name, "game");
+        assert_eq!(package.files.len(), 1);
+        assert_eq!(file.read(&package.files[0]).unwrap(), b"contents");
+        assert!(crate::verify::verify(&file).passed());
+
+        let chunks = file.chunks().unwrap();
+        let names = chunks.children[0]
+            .children
+            .iter()
+            .map(raw::Chunk::name)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["SIDX", "XTRA", "NEWX", "STRS", "FINF", "FEND", "SDAT"]
+        );
+        assert_eq!(
+            chunks.children[0].children[2].header_form,
+            raw::HeaderForm::New
+        );
+    }
+
+    #[test]
+    fn files_without_unknown_chunks_report_none() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("plain.spk");
+        let data = testutil::spk(&[testutil::package("game", vec![Entry::file("a", b"a")])]);
+        std::fs::write(&path, data).unwrap();
+
+        let file = SPKFile::open_with_mode(&path, ParseMode::Lenient).unwrap();
+        assert!(file.unknown_chunks.is_empty());
+    }
 }
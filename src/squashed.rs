@@ -1,13 +1,23 @@
 use std::{
+    collections::VecDeque,
     ffi::OsStr,
-    io::{Cursor, Read as _},
-    path::Path,
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom, Write},
+    os::unix::fs::FileExt as _,
+    path::{Path, PathBuf},
     result::Result,
+    sync::{Arc, Mutex, PoisonError},
 };
 
-use backhand::{FilesystemReader, InnerNode};
+use backhand::{
+    DataSize, FilesystemCompressor, FilesystemReader, FilesystemWriter, Fragment, InnerNode,
+    NodeHeader,
+    compression::{CompressionAction as _, Compressor, DefaultCompressor},
+};
 use thiserror::Error;
 
+use crate::spk;
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Failed to read file: {0}")]
@@ -20,21 +30,262 @@ pub enum Error {
     NoFilesFound,
     #[error("SquashFS file system did not contain a single .spk file as expected")]
     SPKFileNotFound,
+    #[error("Maximum piece size must be greater than zero")]
+    InvalidPieceSize,
+}
+
+impl Error {
+    pub(crate) fn is_io(&self) -> bool {
+        matches!(
+            self,
+            Error::IO(_) | Error::SquashFS(backhand::BackhandError::StdIo(_))
+        )
+    }
 }
 
-pub(crate) fn extract_spk_file(path: &Path) -> Result<Vec<u8>, Error> {
-    let pattern = format!("{}.*", path.with_extension("").to_str().unwrap());
+/// The largest file that can be stored on a FAT32 file system.
+pub const FAT32_MAX_FILE_SIZE: u64 = 0xffff_ffff;
+
+/// Return the ordered paths of the split pieces that `path` belongs to.
+pub(crate) fn piece_paths(path: &Path) -> Result<Vec<PathBuf>, Error> {
+    let pattern = format!(
+        "{}.*",
+        glob::Pattern::escape(&path.with_extension("").to_string_lossy())
+    );
 
     let mut paths: Vec<_> = glob::glob(&pattern)?.filter_map(Result::ok).collect();
     paths.sort();
+    Ok(paths)
+}
+
+/// A reader that presents the ordered piece files of a split image as a single
+/// contiguous stream, in the same way that `affuse` does on the machine.
+///
+/// Clones share the open piece files but have their own position.
+#[derive(Clone)]
+struct SplitReader {
+    /// Each piece along with its offset within the image and its length.
+    pieces: Arc<[(File, u64, u64)]>,
+    len: u64,
+    pos: u64,
+}
+
+impl SplitReader {
+    fn open(paths: &[PathBuf]) -> std::io::Result<Self> {
+        let mut pieces = Vec::with_capacity(paths.len());
+        let mut len = 0;
+        for path in paths {
+            let file = File::open(path)?;
+            let piece_len = file.metadata()?.len();
+            pieces.push((file, len, piece_len));
+            len += piece_len;
+        }
+        Ok(Self {
+            pieces: pieces.into(),
+            len,
+            pos: 0,
+        })
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let index = self
+            .pieces
+            .partition_point(|(_, start, len)| start + len <= offset);
+        let Some((piece, start, piece_len)) = self.pieces.get(index) else {
+            return Ok(0);
+        };
+
+        let offset = offset - start;
+        let len = buf.len().min((piece_len - offset) as usize);
+        piece.read_at(&mut buf[..len], offset)
+    }
+}
+
+impl Read for SplitReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.read_at(buf, self.pos)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for SplitReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = seek_position(pos, self.pos, self.len)?;
+        Ok(self.pos)
+    }
+}
+
+fn seek_position(pos: SeekFrom, current: u64, len: u64) -> std::io::Result<u64> {
+    let pos = match pos {
+        SeekFrom::Start(pos) => Some(pos),
+        SeekFrom::Current(delta) => current.checked_add_signed(delta),
+        SeekFrom::End(delta) => len.checked_add_signed(delta),
+    };
+    pos.ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position",
+        )
+    })
+}
+
+/// The number of decompressed blocks kept in memory by [`SquashedSPKReader`].
+const BLOCK_CACHE_SIZE: usize = 16;
+
+/// A positional reader for the .spk file stored within a split `SquashFS` image.
+///
+/// Data blocks are located using the file's inode and decompressed on demand,
+/// with only a handful of recently used blocks kept in memory.
+pub(crate) struct SquashedSPKReader {
+    pieces: Vec<PathBuf>,
+    inner_path: PathBuf,
+    image: SplitReader,
+    compressor: Compressor,
+    block_size: u64,
+    /// The offset within the image and on-disk size of each full data block.
+    blocks: Vec<(u64, DataSize)>,
+    /// The fragment block holding the tail of the file, and the tail's offset within it.
+    fragment: Option<(Fragment, usize)>,
+    len: u64,
+    /// Recently read blocks by index, with the most recently used last.
+    cache: Mutex<VecDeque<(usize, Arc<Vec<u8>>)>>,
+}
+
+impl SquashedSPKReader {
+    pub(crate) fn pieces(&self) -> &[PathBuf] {
+        &self.pieces
+    }
+
+    pub(crate) fn inner_path(&self) -> &Path {
+        &self.inner_path
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn block(&self, index: usize) -> std::io::Result<Arc<Vec<u8>>> {
+        {
+            // The cache is only a cache, so one left part way through an update by
+            // a panicking thread is still usable.
+            let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(i) = cache.iter().position(|(cached, _)| *cached == index) {
+                let entry = cache.remove(i).unwrap();
+                let block = entry.1.clone();
+                cache.push_back(entry);
+                return Ok(block);
+            }
+        }
+
+        // Decompress without holding the lock so that other threads can make progress.
+        let block = Arc::new(self.load_block(index)?);
+
+        let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        if cache.len() == BLOCK_CACHE_SIZE {
+            cache.pop_front();
+        }
+        cache.push_back((index, block.clone()));
+        Ok(block)
+    }
 
-    let mut buffer: Vec<u8> = Vec::new();
-    for path in paths {
-        buffer.extend(std::fs::read(path)?);
+    /// The number of bytes of the file from the start of the block at `index`,
+    /// which a crafted inode may place past the end of the file.
+    fn remaining_from_block(&self, index: usize) -> std::io::Result<u64> {
+        (index as u64)
+            .checked_mul(self.block_size)
+            .and_then(|start| self.len.checked_sub(start))
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("SquashFS data block {index} starts past the end of the file"),
+                )
+            })
     }
-    let mut reader = Cursor::new(&*buffer);
 
-    let filesystem = FilesystemReader::from_reader(&mut reader)?;
+    #[allow(clippy::cast_possible_truncation)]
+    fn load_block(&self, index: usize) -> std::io::Result<Vec<u8>> {
+        let (start, size, range) = if let Some(&(start, size)) = self.blocks.get(index) {
+            let block_len = self.remaining_from_block(index)?.min(self.block_size);
+            (start, size, 0..block_len as usize)
+        } else if let Some((fragment, offset)) = self.fragment {
+            let tail_len = self.remaining_from_block(self.blocks.len())?;
+            if tail_len > self.block_size {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "SquashFS fragment is larger than a block",
+                ));
+            }
+            (
+                fragment.start,
+                fragment.size,
+                offset..offset + tail_len as usize,
+            )
+        } else {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        };
+
+        let mut data = if size.size() == 0 {
+            // Sparse blocks are not stored within the image.
+            vec![0; range.end]
+        } else {
+            let mut raw = vec![0; size.size() as usize];
+            let mut read = 0;
+            while read < raw.len() {
+                match self.image.read_at(&mut raw[read..], start + read as u64)? {
+                    0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                    n => read += n,
+                }
+            }
+            if size.uncompressed() {
+                raw
+            } else {
+                let mut data = Vec::with_capacity(self.block_size as usize);
+                DefaultCompressor
+                    .decompress(&raw, &mut data, self.compressor)
+                    .map_err(std::io::Error::other)?;
+                data
+            }
+        };
+
+        if data.len() < range.end {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "SquashFS data block is shorter than expected",
+            ));
+        }
+        data.truncate(range.end);
+        data.drain(..range.start);
+        Ok(data)
+    }
+}
+
+impl spk::ReadAt for SquashedSPKReader {
+    #[allow(clippy::cast_possible_truncation)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        if offset >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let block = self.block((offset / self.block_size) as usize)?;
+        let offset = (offset % self.block_size) as usize;
+        let len = buf.len().min(block.len() - offset);
+        buf[..len].copy_from_slice(&block[offset..offset + len]);
+        Ok(len)
+    }
+
+    fn size(&self) -> std::io::Result<u64> {
+        Ok(self.len)
+    }
+}
+
+/// Open the .spk file stored within the split `SquashFS` image that `path` is a piece of.
+pub(crate) fn open_spk_file(path: &Path) -> Result<SquashedSPKReader, Error> {
+    let paths = piece_paths(path)?;
+    let image = SplitReader::open(&paths)?;
+
+    let filesystem = FilesystemReader::from_reader(BufReader::new(image.clone()))?;
     let Some(
         spk_file_node @ backhand::Node {
             inner: InnerNode::File(spk_file, ..),
@@ -54,10 +305,574 @@ pub(crate) fn extract_spk_file(path: &Path) -> Result<Vec<u8>, Error> {
         return Err(Error::SPKFileNotFound)?;
     };
 
-    let mut spk_file_reader = filesystem.file(spk_file).reader();
-    let mut spk_file_contents = vec![];
-    spk_file_contents.reserve_exact(spk_file.file_len());
-    spk_file_reader.read_to_end(&mut spk_file_contents)?;
+    let mut blocks = Vec::with_capacity(spk_file.block_sizes().len());
+    let mut start = spk_file.blocks_start();
+    for &size in spk_file.block_sizes() {
+        blocks.push((start, size));
+        start += u64::from(size.size());
+    }
+
+    let fragment = filesystem
+        .file(spk_file)
+        .fragment()
+        .map(|fragment| (*fragment, spk_file.block_offset() as usize));
+
+    Ok(SquashedSPKReader {
+        image,
+        inner_path: spk_file_node.fullpath.clone(),
+        pieces: paths,
+        compressor: filesystem.compressor,
+        block_size: u64::from(filesystem.block_size),
+        blocks,
+        fragment,
+        len: spk_file.file_len() as u64,
+        cache: Mutex::new(VecDeque::with_capacity(BLOCK_CACHE_SIZE)),
+    })
+}
+
+/// A seekable writer that spreads its output across numbered piece files of at
+/// most `max_piece_size` bytes each.
+///
+/// Pieces are written to temporary names since the final names include the total
+/// number of pieces, which is only known once writing has finished.
+struct SplitWriter {
+    base: PathBuf,
+    max_piece_size: u64,
+    pieces: Vec<File>,
+    len: u64,
+    pos: u64,
+}
+
+impl SplitWriter {
+    fn new(base: PathBuf, max_piece_size: u64) -> Self {
+        Self {
+            base,
+            max_piece_size,
+            pieces: Vec::new(),
+            len: 0,
+            pos: 0,
+        }
+    }
+
+    fn temporary_path(&self, index: usize) -> PathBuf {
+        let mut path = self.base.clone().into_os_string();
+        path.push(format!(".partial.{index:03}"));
+        path.into()
+    }
+
+    fn piece(&mut self, index: usize) -> std::io::Result<&mut File> {
+        while self.pieces.len() <= index {
+            let path = self.temporary_path(self.pieces.len());
+            let file = File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)?;
+            self.pieces.push(file);
+        }
+        Ok(&mut self.pieces[index])
+    }
+
+    /// Rename the pieces to `<base>.<count>.<index>` and return their paths.
+    ///
+    /// If a piece cannot be renamed, the pieces that were already renamed are
+    /// removed and the rest are left for [`SplitWriter::discard`].
+    fn finish(&mut self) -> std::io::Result<Vec<PathBuf>> {
+        self.pieces.iter().try_for_each(File::sync_all)?;
+
+        // Pieces left by an earlier split into a different number of pieces
+        // would be mistaken for part of this one, so remove them first.
+        let pattern = format!(
+            "{}.[0-9][0-9][0-9].[0-9][0-9][0-9]",
+            glob::Pattern::escape(&self.base.to_string_lossy())
+        );
+        for path in glob::glob(&pattern)
+            .map_err(std::io::Error::other)?
+            .filter_map(Result::ok)
+        {
+            std::fs::remove_file(path)?;
+        }
+
+        let count = self.pieces.len();
+        let mut paths = Vec::with_capacity(count);
+        for index in 0..count {
+            let mut path = self.base.clone().into_os_string();
+            path.push(format!(".{count:03}.{index:03}"));
+            let path = PathBuf::from(path);
+            if let Err(err) = std::fs::rename(self.temporary_path(index), &path) {
+                for path in &paths {
+                    let _ = std::fs::remove_file(path);
+                }
+                return Err(err);
+            }
+            paths.push(path);
+        }
+
+        self.pieces.clear();
+        Ok(paths)
+    }
+
+    /// Remove the temporary pieces written so far.
+    fn discard(&self) {
+        for index in 0..self.pieces.len() {
+            let _ = std::fs::remove_file(self.temporary_path(index));
+        }
+    }
+}
+
+impl Write for SplitWriter {
+    #[allow(clippy::cast_possible_truncation)]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let index = (self.pos / self.max_piece_size) as usize;
+        let offset = self.pos % self.max_piece_size;
+        let len = buf.len().min((self.max_piece_size - offset) as usize);
+
+        let piece = self.piece(index)?;
+        piece.seek(SeekFrom::Start(offset))?;
+        let written = piece.write(&buf[..len])?;
+        self.pos += written as u64;
+        self.len = self.len.max(self.pos);
+        Ok(written)
+    }
 
-    Ok(spk_file_contents)
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.pieces.iter_mut().try_for_each(File::flush)
+    }
+}
+
+impl Seek for SplitWriter {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = seek_position(pos, self.pos, self.len)?;
+        Ok(self.pos)
+    }
+}
+
+/// Wrap the .spk file at `spk_path` in a `SquashFS` file system and write it to
+/// `output_dir`, split into pieces of at most `max_piece_size` bytes.
+pub(crate) fn write_split_spk_file(
+    spk_path: &Path,
+    output_dir: &Path,
+    max_piece_size: u64,
+) -> Result<Vec<PathBuf>, Error> {
+    if max_piece_size == 0 {
+        return Err(Error::InvalidPieceSize);
+    }
+
+    let Some(file_name) = spk_path
+        .file_name()
+        .filter(|name| Path::new(name).extension() == Some(OsStr::new("spk")))
+    else {
+        return Err(Error::SPKFileNotFound);
+    };
+
+    let mut filesystem = FilesystemWriter::default();
+    filesystem.set_compressor(FilesystemCompressor::new(Compressor::Gzip, None)?);
+    filesystem.set_root_mode(0o755);
+    filesystem.push_file(
+        File::open(spk_path)?,
+        file_name,
+        NodeHeader::new(0o644, 0, 0, 0),
+    )?;
+
+    let mut writer = SplitWriter::new(output_dir.join(file_name), max_piece_size);
+    write_pieces(&mut filesystem, &mut writer).inspect_err(|_| writer.discard())
+}
+
+fn write_pieces(
+    filesystem: &mut FilesystemWriter<'_, '_, '_>,
+    writer: &mut SplitWriter,
+) -> Result<Vec<PathBuf>, Error> {
+    filesystem.write(&mut *writer)?;
+    writer.flush()?;
+    Ok(writer.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use backhand::{
+        BackhandError, MIN_BLOCK_SIZE, SuperBlock, compression::CompressionAction, kind::Kind,
+    };
+
+    use super::*;
+    use crate::{PackageType, spk::ReadAt as _, write};
+
+    /// Bytes that do not compress well, so that the image spans several pieces.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state.to_le_bytes()[0]
+            })
+            .collect()
+    }
+
+    fn create_spk(dir: &Path) -> PathBuf {
+        let package = dir.join("package");
+        std::fs::create_dir_all(package.join("etc")).unwrap();
+        std::fs::write(package.join("etc/big"), noise(300_000, 1)).unwrap();
+        std::fs::write(package.join("etc/small"), b"small file").unwrap();
+
+        let spk_path = dir.join("update.spk");
+        write::create(
+            &[write::PackageSource {
+                name: "package".to_string(),
+                version: (1, 0, 0),
+                type_: PackageType::Game,
+                directory: package,
+            }],
+            &spk_path,
+        )
+        .unwrap();
+        spk_path
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn split_image_reopens_with_the_same_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let spk_path = create_spk(dir.path());
+        let output = dir.path().join("split");
+        std::fs::create_dir(&output).unwrap();
+
+        let pieces = write_split_spk_file(&spk_path, &output, 64 * 1024).unwrap();
+        let count = pieces.len();
+        assert!(count > 1);
+        let expected_names = (0..count)
+            .map(|index| format!("update.spk.{count:03}.{index:03}"))
+            .collect::<Vec<_>>();
+        assert_eq!(file_names(&output), expected_names);
+        for piece in &pieces {
+            assert!(piece.metadata().unwrap().len() <= 64 * 1024);
+        }
+
+        let original = spk::SPKFile::open(&spk_path).unwrap();
+        let split = spk::SPKFile::open(&output).unwrap();
+        assert!(matches!(split.source, spk::Source::SplitSquashed { .. }));
+        assert_eq!(split.packages, original.packages);
+        for file in &original.packages[0].files {
+            assert_eq!(split.read(file).unwrap(), original.read(file).unwrap());
+        }
+        assert!(crate::verify::verify(&split).passed());
+    }
+
+    #[test]
+    fn failed_split_leaves_no_pieces_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let spk_path = create_spk(dir.path());
+        let first = dir.path().join("first");
+        std::fs::create_dir(&first).unwrap();
+        let count = write_split_spk_file(&spk_path, &first, 64 * 1024)
+            .unwrap()
+            .len();
+
+        // A directory in place of the second piece stops it from being renamed,
+        // after the first piece has been.
+        let output = dir.path().join("second");
+        let obstacle = format!("update.spk.{count:03}.001");
+        std::fs::create_dir_all(output.join(&obstacle).join("file")).unwrap();
+
+        assert!(write_split_spk_file(&spk_path, &output, 64 * 1024).is_err());
+        assert_eq!(file_names(&output), [obstacle]);
+    }
+
+    #[test]
+    fn splitting_again_replaces_the_previous_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let spk_path = create_spk(dir.path());
+        let output = dir.path().join("split");
+        std::fs::create_dir(&output).unwrap();
+        std::fs::write(output.join("unrelated.spk.001.000"), b"unrelated").unwrap();
+
+        assert!(
+            write_split_spk_file(&spk_path, &output, 64 * 1024)
+                .unwrap()
+                .len()
+                > 1
+        );
+        let pieces = write_split_spk_file(&spk_path, &output, 1024 * 1024).unwrap();
+        assert_eq!(pieces.len(), 1);
+        assert_eq!(
+            file_names(&output),
+            ["unrelated.spk.001.000", "update.spk.001.000"]
+        );
+
+        let original = spk::SPKFile::open(&spk_path).unwrap();
+        let split = spk::SPKFile::open(&pieces[0]).unwrap();
+        assert_eq!(split.packages, original.packages);
+        assert!(crate::verify::verify(&split).passed());
+    }
+
+    const BLOCK_SIZE: usize = MIN_BLOCK_SIZE as usize;
+
+    /// A compressor whose output never beats the input, so that every data and
+    /// metadata block is stored uncompressed.
+    struct Stored;
+
+    impl CompressionAction for Stored {
+        fn decompress(
+            &self,
+            bytes: &[u8],
+            out: &mut Vec<u8>,
+            compressor: Compressor,
+        ) -> Result<(), BackhandError> {
+            DefaultCompressor.decompress(bytes, out, compressor)
+        }
+
+        fn compress(
+            &self,
+            bytes: &[u8],
+            _: FilesystemCompressor,
+            _: u32,
+        ) -> Result<Vec<u8>, BackhandError> {
+            let mut stored = bytes.to_vec();
+            stored.push(0);
+            Ok(stored)
+        }
+
+        fn compression_options(
+            &self,
+            superblock: &mut SuperBlock,
+            kind: &Kind,
+            fs_compressor: FilesystemCompressor,
+        ) -> Result<Vec<u8>, BackhandError> {
+            DefaultCompressor.compression_options(superblock, kind, fs_compressor)
+        }
+    }
+
+    static STORED: Stored = Stored;
+
+    /// Write a single piece image holding `contents` as `inner.spk`, with small blocks.
+    fn write_image(dir: &Path, contents: &[u8], kind: Option<Kind>) -> PathBuf {
+        let mut filesystem = FilesystemWriter::default();
+        if let Some(kind) = kind {
+            filesystem.set_kind(kind);
+        }
+        filesystem.set_block_size(MIN_BLOCK_SIZE);
+        filesystem.set_compressor(FilesystemCompressor::new(Compressor::Gzip, None).unwrap());
+        filesystem
+            .push_file(contents, "inner.spk", NodeHeader::new(0o644, 0, 0, 0))
+            .unwrap();
+
+        let path = dir.join("image.spk.001.000");
+        filesystem.write(File::create(&path).unwrap()).unwrap();
+        path
+    }
+
+    /// Read the inner file with backhand's own reader.
+    fn backhand_contents(path: &Path) -> Vec<u8> {
+        let filesystem =
+            FilesystemReader::from_reader(BufReader::new(File::open(path).unwrap())).unwrap();
+        let node = filesystem
+            .files()
+            .find(|node| node.fullpath == Path::new("/inner.spk"));
+        let Some(backhand::Node {
+            inner: InnerNode::File(file),
+            ..
+        }) = node
+        else {
+            panic!("inner.spk not found");
+        };
+        // backhand prefetches half as many blocks as there are threads, and so
+        // reads nothing at all on a single core machine.
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        pool.install(|| {
+            let mut contents = Vec::new();
+            filesystem
+                .file(file)
+                .reader()
+                .read_to_end(&mut contents)
+                .unwrap();
+            contents
+        })
+    }
+
+    fn read_exact_at(reader: &SquashedSPKReader, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        let mut read = 0;
+        while read < len {
+            match reader
+                .read_at(&mut buf[read..], offset + read as u64)
+                .unwrap()
+            {
+                0 => break,
+                n => read += n,
+            }
+        }
+        buf.truncate(read);
+        buf
+    }
+
+    /// Check whole and piecewise reads, including ones that straddle block
+    /// boundaries or run past the end, against backhand's reader.
+    fn assert_reads_match(path: &Path, expected: &[u8]) {
+        let reader = open_spk_file(path).unwrap();
+        assert_eq!(backhand_contents(path), expected);
+        assert_eq!(reader.len(), expected.len() as u64);
+        assert_eq!(read_exact_at(&reader, 0, expected.len() + 10), expected);
+
+        let len = expected.len();
+        for (offset, read_len) in [
+            (0, 1),
+            (BLOCK_SIZE - 3, 7),
+            (BLOCK_SIZE, BLOCK_SIZE),
+            (BLOCK_SIZE / 2, 2 * BLOCK_SIZE),
+            (len - 100, 50),
+            (len - 100, 500),
+            (len - 1, 1),
+            (len, 1),
+        ] {
+            assert_eq!(
+                read_exact_at(&reader, offset as u64, read_len),
+                expected[offset.min(len)..(offset + read_len).min(len)],
+                "read of {read_len} bytes at {offset}",
+            );
+        }
+
+        // Fill the cache past its capacity, then read every block again in reverse.
+        for index in (0..len.div_ceil(BLOCK_SIZE)).chain((0..len.div_ceil(BLOCK_SIZE)).rev()) {
+            let offset = index * BLOCK_SIZE;
+            assert_eq!(
+                read_exact_at(&reader, offset as u64, BLOCK_SIZE),
+                expected[offset..(offset + BLOCK_SIZE).min(len)],
+            );
+        }
+    }
+
+    #[test]
+    fn reads_compressed_and_stored_blocks_with_a_short_last_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut contents = Vec::new();
+        for index in 0..20 {
+            if index % 3 == 0 {
+                contents.extend(noise(BLOCK_SIZE, index));
+            } else {
+                contents.extend(vec![index.to_le_bytes()[0]; BLOCK_SIZE]);
+            }
+        }
+        contents.extend(b"short last block");
+
+        // backhand only uses fragments for files smaller than a block.
+        let path = write_image(dir.path(), &contents, None);
+        let reader = open_spk_file(&path).unwrap();
+        assert_eq!(reader.blocks.len(), 21);
+        assert!(reader.fragment.is_none());
+        assert!(reader.blocks.iter().any(|(_, size)| size.uncompressed()));
+        assert!(reader.blocks.iter().any(|(_, size)| !size.uncompressed()));
+        assert_reads_match(&path, &contents);
+    }
+
+    #[test]
+    fn reads_a_tail_packed_into_a_fragment() {
+        let dir = tempfile::tempdir().unwrap();
+        let contents = noise(BLOCK_SIZE - 1000, 2);
+        let path = write_image(dir.path(), &contents, None);
+        let reader = open_spk_file(&path).unwrap();
+        assert!(reader.blocks.is_empty());
+        assert!(reader.fragment.is_some());
+        assert_reads_match(&path, &contents);
+    }
+
+    #[test]
+    fn blocks_past_the_end_of_the_file_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let contents = noise(3 * BLOCK_SIZE, 4);
+        let path = write_image(dir.path(), &contents, None);
+        let mut reader = open_spk_file(&path).unwrap();
+        assert_eq!(reader.blocks.len(), 3);
+
+        // As if the inode listed more blocks than its length needs.
+        reader.len = BLOCK_SIZE as u64;
+        let err = reader.load_block(2).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "SquashFS data block 2 starts past the end of the file"
+        );
+
+        let other = dir.path().join("other");
+        std::fs::create_dir(&other).unwrap();
+        let fragment = open_spk_file(&write_image(&other, b"tail", None))
+            .unwrap()
+            .fragment;
+        assert!(fragment.is_some());
+        reader.fragment = fragment;
+        assert_eq!(
+            reader.load_block(3).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+        reader.len = 10 * BLOCK_SIZE as u64;
+        assert_eq!(
+            reader.load_block(3).unwrap_err().to_string(),
+            "SquashFS fragment is larger than a block"
+        );
+    }
+
+    #[test]
+    fn reads_continue_after_a_thread_panics_while_holding_the_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let contents = noise(3 * BLOCK_SIZE, 5);
+        let path = write_image(dir.path(), &contents, None);
+        let reader = open_spk_file(&path).unwrap();
+        assert_eq!(read_exact_at(&reader, 0, 10), contents[..10]);
+
+        std::thread::scope(|scope| {
+            let result = scope
+                .spawn(|| {
+                    let _cache = reader.cache.lock().unwrap();
+                    panic!("poison the cache");
+                })
+                .join();
+            assert!(result.is_err());
+        });
+        assert!(reader.cache.is_poisoned());
+        assert_eq!(read_exact_at(&reader, 0, contents.len()), contents);
+    }
+
+    #[test]
+    fn reads_sparse_blocks_as_zeros() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut contents = noise(2 * BLOCK_SIZE, 3);
+        contents.extend(vec![0; BLOCK_SIZE]);
+        let path = write_image(dir.path(), &contents, Some(Kind::new(&STORED)));
+
+        // backhand never writes sparse blocks, so mark the last block as sparse
+        // within the (uncompressed) inode table. Being last, no other block's
+        // position depends on its size.
+        let mut image = std::fs::read(&path).unwrap();
+        // The on-disk size of a block stored uncompressed has bit 24 set.
+        let stored = (MIN_BLOCK_SIZE | 1 << 24).to_le_bytes();
+        let block_list = stored.repeat(3);
+        let matches = image
+            .windows(block_list.len())
+            .enumerate()
+            .filter(|(_, window)| *window == block_list)
+            .map(|(offset, _)| offset)
+            .collect::<Vec<_>>();
+        assert_eq!(matches.len(), 1);
+        image[matches[0] + 8..matches[0] + 12].fill(0);
+        std::fs::write(&path, image).unwrap();
+
+        let reader = open_spk_file(&path).unwrap();
+        assert_eq!(reader.blocks[2].1.size(), 0);
+        assert_reads_match(&path, &contents);
+    }
 }
@@ -0,0 +1,134 @@
+//! Crafted SPK files for tests, including ones that the writer refuses to produce.
+
+use binrw::NullString;
+
+use crate::{
+    chunks::{self, PackageType},
+    verify,
+};
+
+/// A file within a crafted package, whose digests are computed over `data`.
+pub(crate) struct Entry {
+    pub name: String,
+    pub mode: u16,
+    pub data: Vec<u8>,
+    /// The size recorded for the file, which differs from `data`'s length for
+    /// data in an unsupported encoding.
+    pub size: u64,
+}
+
+impl Entry {
+    pub(crate) fn file(name: &str, contents: &[u8]) -> Self {
+        Self::with_mode(name, 0o100_644, contents)
+    }
+
+    pub(crate) fn directory(name: &str) -> Self {
+        Self::with_mode(name, 0o040_755, b"")
+    }
+
+    pub(crate) fn symlink(name: &str, target: &str) -> Self {
+        Self::with_mode(name, 0o120_777, target.as_bytes())
+    }
+
+    pub(crate) fn with_mode(name: &str, mode: u16, data: &[u8]) -> Self {
+        Self {
+            name: name.to_string(),
+            mode,
+            data: data.to_vec(),
+            size: data.len() as u64,
+        }
+    }
+}
+
+pub(crate) struct Package {
+    pub name: String,
+    pub version: (u8, u8, u8),
+    pub type_: PackageType,
+    pub entries: Vec<Entry>,
+    /// Raw chunks written between `SIDX` and `STRS`.
+    pub extra_chunks: Vec<u8>,
+}
+
+pub(crate) fn package(name: &str, entries: Vec<Entry>) -> Package {
+    Package {
+        name: name.to_string(),
+        version: (1, 0, 0),
+        type_: PackageType::Game,
+        entries,
+        extra_chunks: Vec::new(),
+    }
+}
+
+fn write_le<T>(value: &T, out: &mut Vec<u8>)
+where
+    T: for<'a> binrw::BinWrite<Args<'a> = ()>,
+{
+    let mut cursor = std::io::Cursor::new(Vec::new());
+    value.write_le(&mut cursor).unwrap();
+    out.extend(cursor.into_inner());
+}
+
+fn package_body(package: &Package) -> Vec<u8> {
+    let mut package_name = [0; 0x20];
+    package_name[..package.name.len()].copy_from_slice(package.name.as_bytes());
+
+    let mut body = Vec::new();
+    write_le(
+        &chunks::SIDX::new(package_name, package.version, package.type_),
+        &mut body,
+    );
+    body.extend_from_slice(&package.extra_chunks);
+
+    let mut string_data = Vec::new();
+    let mut file_infos = Vec::new();
+    let mut data = Vec::new();
+    for entry in &package.entries {
+        let digests = verify::digest(entry.data.as_slice()).unwrap();
+        file_infos.push(chunks::FileInfo::FINF(chunks::FINF {
+            byte_len: chunks::FINF::BYTE_LEN,
+            filename: NullString::from(entry.name.as_str()),
+            filename_offset: u32::try_from(string_data.len()).unwrap(),
+            file_size: u32::try_from(entry.size).unwrap(),
+            data_offset: u32::try_from(data.len()).unwrap(),
+            data_size: u32::try_from(entry.data.len()).unwrap(),
+            mode: entry.mode,
+            pad_before_hmac: [0; 3],
+            data_hmac: digests.hmac,
+            data_md5: digests.md5,
+            pad_after_md5: [0; 3],
+        }));
+        string_data.extend_from_slice(entry.name.as_bytes());
+        string_data.push(0);
+        data.extend_from_slice(&entry.data);
+    }
+    file_infos.push(chunks::FileInfo::FEND(chunks::FEND::new()));
+
+    write_le(&chunks::STRS::new(string_data).unwrap(), &mut body);
+    for file_info in &file_infos {
+        write_le(file_info, &mut body);
+    }
+    write_le(&chunks::SDAT::new(data.len() as u64), &mut body);
+    body.extend_from_slice(&data);
+    body
+}
+
+/// Build a SPK file containing `packages`.
+pub(crate) fn spk(packages: &[Package]) -> Vec<u8> {
+    let mut packages_data = Vec::new();
+    for package in packages {
+        let body = package_body(package);
+        write_le(&chunks::SPK0::new(body.len() as u64), &mut packages_data);
+        packages_data.extend_from_slice(&body);
+    }
+
+    let mut spk = Vec::new();
+    write_le(
+        &chunks::SPKS::new(
+            4 + packages_data.len() as u64,
+            u32::try_from(packages.len()).unwrap(),
+        ),
+        &mut spk,
+    );
+    spk.extend_from_slice(&packages_data);
+    spk
+}
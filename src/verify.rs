@@ -1,114 +1,289 @@
-use anyhow::Context as _;
 use hmac::{self, Mac as _};
-use md5::Digest;
+use md5::Digest as _;
 use rayon::iter::{IntoParallelRefIterator as _, ParallelIterator as _};
 use sha1;
+use thiserror::Error;
 
-use crate::spk;
+use crate::{chunks::PackageType, spk};
 
+/// The result of verifying every file within a SPK file.
 #[derive(Debug)]
-struct VerificationResult {
-    md5: bool,
-    hmac: bool,
+pub struct VerificationReport {
+    pub packages: Vec<PackageReport>,
 }
 
-fn verify_one_file(
-    file: &spk::SPKFile,
-    file_info: &spk::FileInfo,
-) -> anyhow::Result<VerificationResult> {
-    let contents = file.read(file_info)?;
+#[derive(Debug)]
+pub struct PackageReport {
+    pub name: String,
+    pub version: (u8, u8, u8),
+    pub type_: PackageType,
+    /// Sorted by file name.
+    pub files: Vec<FileReport>,
+}
 
-    let md5_digest = md5::Md5::digest(&contents);
-    let md5_result = md5_digest == file_info.md5.into();
+#[derive(Debug)]
+pub struct FileReport {
+    pub file: spk::FileInfo,
+    /// The digests computed from the file's data, or why they could not be.
+    pub result: Result<Digests, FileError>,
+}
 
-    let mut sha1_hmac = hmac::Hmac::<sha1::Sha1>::new_from_slice(spk::HMAC_KEY)?;
-    sha1_hmac.update(&contents);
-    let sha1_hmac_digest = sha1_hmac.finalize().into_bytes();
-    let sha1_hmac_result = sha1_hmac_digest == file_info.hmac.into();
+/// Why the digests of a file's data could not be computed.
+///
+/// Digests that were computed but do not match are reported by
+/// [`FileReport::md5_matches`] and [`FileReport::hmac_matches`] instead.
+#[derive(Error, Debug)]
+pub enum FileError {
+    #[error("Failed to read file data: {0}")]
+    Io(#[source] std::io::Error),
+    #[error("File data ended after {len} of {expected} bytes")]
+    Truncated { len: u64, expected: u64 },
+    #[error(transparent)]
+    Encoding(#[from] spk::ReadError),
+}
 
-    Ok(VerificationResult {
-        md5: md5_result,
-        hmac: sha1_hmac_result,
-    })
+impl FileError {
+    /// Whether the file's data could not be read, rather than being read and
+    /// found not to be the file's contents.
+    #[must_use]
+    pub fn is_io(&self) -> bool {
+        matches!(
+            self,
+            FileError::Io(_) | FileError::Encoding(spk::ReadError::IOError(_))
+        )
+    }
 }
 
-pub(crate) fn verify_all(file: &spk::SPKFile) -> anyhow::Result<()> {
-    // Verify files from all packages in parallel, collecting only the failures.
-    let failures = file
-        .packages
-        .par_iter()
-        .map(|package| {
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Digests {
+    pub len: u64,
+    pub md5: [u8; 16],
+    pub hmac: [u8; 20],
+}
+
+impl VerificationReport {
+    #[must_use]
+    pub fn passed(&self) -> bool {
+        self.packages.iter().all(PackageReport::passed)
+    }
+
+    /// Iterate over the files that failed verification, along with their package.
+    pub fn failures(&self) -> impl Iterator<Item = (&PackageReport, &FileReport)> {
+        self.packages.iter().flat_map(|package| {
             package
                 .files
-                .par_iter()
-                .map(|file_info| -> anyhow::Result<_> {
-                    let result = verify_one_file(file, file_info).with_context(|| {
-                        format!(
-                            "Error attempting to verify file {} in package {}",
-                            file_info.name, package.name
-                        )
-                    })?;
-                    Ok((file_info, result.md5 && result.hmac))
-                })
-        })
-        .flatten()
-        .filter(|result| match result {
-            Ok((_, result)) => !result,
-            Err(_) => true,
+                .iter()
+                .filter(|file| !file.passed())
+                .map(move |file| (package, file))
         })
-        .collect::<Result<Vec<_>, _>>()?;
+    }
+}
+
+impl PackageReport {
+    #[must_use]
+    pub fn passed(&self) -> bool {
+        self.files.iter().all(FileReport::passed)
+    }
+}
+
+impl FileReport {
+    #[must_use]
+    pub fn md5_matches(&self) -> bool {
+        self.result
+            .as_ref()
+            .is_ok_and(|digests| digests.md5 == self.file.md5)
+    }
+
+    #[must_use]
+    pub fn hmac_matches(&self) -> bool {
+        self.result
+            .as_ref()
+            .is_ok_and(|digests| digests.hmac == self.file.hmac)
+    }
+
+    #[must_use]
+    pub fn passed(&self) -> bool {
+        self.md5_matches() && self.hmac_matches()
+    }
+}
+
+/// Compute the MD5 and HMAC-SHA1 of everything read from `reader`.
+pub(crate) fn digest<R: std::io::Read>(mut reader: R) -> std::io::Result<Digests> {
+    let mut md5 = md5::Md5::new();
+    let mut sha1_hmac = hmac::Hmac::<sha1::Sha1>::new_from_slice(spk::HMAC_KEY)
+        .expect("HMAC accepts keys of any length");
+
+    let mut buf = vec![0; 64 * 1024];
+    let mut len = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        md5.update(&buf[..n]);
+        sha1_hmac.update(&buf[..n]);
+        len += n as u64;
+    }
+
+    Ok(Digests {
+        len,
+        md5: md5.finalize().into(),
+        hmac: sha1_hmac.finalize().into_bytes().into(),
+    })
+}
+
+/// Verify the MD5 and HMAC of a single file within `file`.
+#[must_use]
+pub fn verify_file(file: &spk::SPKFile, file_info: &spk::FileInfo) -> FileReport {
+    let result = digest(file.reader(file_info))
+        .map_err(FileError::Io)
+        .and_then(|digests| {
+            if digests.len != file_info.data_size {
+                return Err(FileError::Truncated {
+                    len: digests.len,
+                    expected: file_info.data_size,
+                });
+            }
+            // The digests cover the stored data, so also check that it is the file's contents.
+            file.check_encoding(file_info)?;
+            Ok(digests)
+        });
+
+    FileReport {
+        file: file_info.clone(),
+        result,
+    }
+}
 
+/// Verify every file within `packages`, which must belong to `file`, failing
+/// if any of them cannot be read or does not match its digests.
+pub(crate) fn verify_all(file: &spk::SPKFile, packages: &[spk::Package]) -> anyhow::Result<()> {
+    let report = verify_packages(file, packages);
+    if let Some((
+        package,
+        FileReport {
+            file,
+            result: Err(err),
+        },
+    )) = report.failures().find(|(_, file)| file.result.is_err())
+    {
+        anyhow::bail!(
+            "Error attempting to verify file {} in package {}: {err}",
+            file.name,
+            package.name
+        );
+    }
+
+    let failures = report
+        .failures()
+        .map(|(_, file)| file.file.name.clone())
+        .collect::<Vec<_>>();
     if failures.is_empty() {
         return Ok(());
     }
 
-    anyhow::bail!(
-        "Some files failed verification: {}",
-        failures
-            .iter()
-            .map(|(file_info, _)| file_info.name.clone())
-            .collect::<Vec<_>>()
-            .join(", ")
-    );
+    anyhow::bail!("Some files failed verification: {}", failures.join(", "));
 }
 
-fn check(value: bool) -> &'static str {
-    if value { "✔" } else { "✗" }
+/// Verify the MD5 and HMAC of every file within `file`, reading files in parallel.
+#[must_use]
+pub fn verify(file: &spk::SPKFile) -> VerificationReport {
+    verify_packages(file, &file.packages)
 }
 
-pub fn verify(file: &mut spk::SPKFile) -> anyhow::Result<()> {
-    for (i, package) in file.packages.iter().enumerate() {
-        if i > 0 {
-            println!("\n");
-        }
+fn verify_packages(file: &spk::SPKFile, packages: &[spk::Package]) -> VerificationReport {
+    let packages = packages
+        .par_iter()
+        .map(|package| {
+            let mut files = package
+                .files
+                .par_iter()
+                .map(|file_info| verify_file(file, file_info))
+                .collect::<Vec<_>>();
+            files.sort_by(|a, b| a.file.name.cmp(&b.file.name));
 
-        println!("Package: {}", package.name);
-        println!(
-            "Version: {}.{}.{}",
-            package.version.0, package.version.1, package.version.2
-        );
+            PackageReport {
+                name: package.name.clone(),
+                version: package.version,
+                type_: package.type_,
+                files,
+            }
+        })
+        .collect();
+
+    VerificationReport { packages }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, Entry};
+
+    fn entries() -> Vec<Entry> {
+        vec![
+            Entry::file("a", b"first file"),
+            Entry::file("b", b"second file"),
+        ]
+    }
 
-        let mut results = package
-            .files
-            .par_iter()
-            .map(|file_info| -> anyhow::Result<_> {
-                Ok((file_info, verify_one_file(file, file_info)?))
-            })
-            .collect::<Result<Vec<_>, _>>()?;
-
-        results.sort_by(|a, b| a.0.name.cmp(&b.0.name));
-
-        for (file_info, result) in results {
-            println!(
-                "{:165} mode={:o} size={:11}  md5: {}  hmac: {}  ",
-                format!("{}{}", package.type_.path_prefix(), file_info.name),
-                file_info.mode,
-                file_info.size,
-                check(result.md5),
-                check(result.hmac)
-            );
-        }
+    #[test]
+    fn intact_files_pass() {
+        let data = testutil::spk(&[testutil::package("game", entries())]);
+        let file = spk::SPKFile::parse_bytes(data).unwrap();
+        let report = verify(&file);
+        assert!(report.passed());
+        assert_eq!(report.failures().count(), 0);
+        let digests = report.packages[0].files[0].result.as_ref().unwrap();
+        assert_eq!(digests.len, 10);
     }
 
-    Ok(())
+    #[test]
+    fn corrupted_data_fails_both_digests() {
+        let mut data = testutil::spk(&[testutil::package("game", entries())]);
+        let offset = data.windows(6).position(|w| w == b"second").unwrap();
+        data[offset] = b'S';
+
+        let file = spk::SPKFile::parse_bytes(data).unwrap();
+        let report = verify(&file);
+        assert!(!report.passed());
+        let failures = report.failures().collect::<Vec<_>>();
+        assert_eq!(failures.len(), 1);
+        let (package, failure) = failures[0];
+        assert_eq!(package.name, "game");
+        assert_eq!(failure.file.name, "b");
+        assert!(failure.result.is_ok());
+        assert!(!failure.md5_matches());
+        assert!(!failure.hmac_matches());
+        assert_eq!(
+            verify_all(&file, &file.packages).unwrap_err().to_string(),
+            "Some files failed verification: b"
+        );
+    }
+
+    #[test]
+    fn data_in_an_unsupported_encoding_is_a_file_error() {
+        let mut entry = Entry::file("a", b"encoded");
+        entry.size = 100;
+        let data = testutil::spk(&[testutil::package("game", vec![entry])]);
+
+        let file = spk::SPKFile::parse_bytes(data).unwrap();
+        let report = verify(&file);
+        let failure = &report.packages[0].files[0];
+        assert!(matches!(
+            failure.result,
+            Err(FileError::Encoding(
+                spk::ReadError::UnsupportedEncoding { .. }
+            ))
+        ));
+        assert!(!failure.passed());
+        assert!(!failure.result.as_ref().unwrap_err().is_io());
+        assert!(
+            verify_all(&file, &file.packages)
+                .unwrap_err()
+                .to_string()
+                .starts_with("Error attempting to verify file a in package game: ")
+        );
+    }
 }
@@ -0,0 +1,383 @@
+use std::{
+    io::{Read as _, Write},
+    os::unix::fs::MetadataExt as _,
+    path::{Path, PathBuf},
+};
+
+use binrw::{BinWrite as _, NullString, io::NoSeek};
+use thiserror::Error;
+
+use crate::{
+    chunks::{self, PackageType},
+    squashed, verify,
+};
+
+pub use crate::squashed::FAT32_MAX_FILE_SIZE;
+
+#[derive(Error, Debug)]
+pub enum WriteError {
+    #[error("Failed to write file: {0}")]
+    IOError(#[from] std::io::Error),
+    #[error("Failed to serialize file: {0}")]
+    Serialize(#[from] binrw::Error),
+    #[error("Failed to write SquashFS file: {0}")]
+    SquashFS(#[from] squashed::Error),
+    #[error("Package name is longer than 32 bytes: {0}")]
+    PackageNameTooLong(String),
+    #[error("Too many packages: {0}")]
+    TooManyPackages(usize),
+    #[error("File name contained invalid UTF-8: {0}")]
+    InvalidFileName(PathBuf),
+    #[error("String table for package {0} exceeds 4 GiB")]
+    StringTableTooLarge(String),
+    #[error("Unsupported file type: {0}")]
+    UnsupportedFileType(PathBuf),
+    #[error("File changed while it was being written: {0}")]
+    FileChanged(PathBuf),
+}
+
+/// A directory tree to be written as a single package within a SPK file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageSource {
+    pub name: String,
+    pub version: (u8, u8, u8),
+    pub type_: PackageType,
+    pub directory: PathBuf,
+}
+
+struct FileEntry {
+    name: String,
+    path: PathBuf,
+    size: u64,
+    mode: u16,
+    hmac: [u8; 20],
+    md5: [u8; 16],
+}
+
+fn collect_files(
+    root: &Path,
+    directory: &Path,
+    files: &mut Vec<FileEntry>,
+) -> Result<(), WriteError> {
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        let metadata = std::fs::symlink_metadata(&path)?;
+        if metadata.is_dir() {
+            collect_files(root, &path, files)?;
+            continue;
+        }
+        if !metadata.is_file() {
+            return Err(WriteError::UnsupportedFileType(path));
+        }
+
+        let name = path
+            .strip_prefix(root)
+            .ok()
+            .and_then(Path::to_str)
+            .ok_or_else(|| WriteError::InvalidFileName(path.clone()))?
+            .to_string();
+
+        #[allow(clippy::cast_possible_truncation)]
+        files.push(FileEntry {
+            name,
+            size: metadata.len(),
+            mode: metadata.mode() as u16,
+            path,
+            hmac: [0; 20],
+            md5: [0; 16],
+        });
+    }
+    Ok(())
+}
+
+fn hash_file(entry: &mut FileEntry) -> Result<(), WriteError> {
+    let digests = verify::digest(std::fs::File::open(&entry.path)?)?;
+    if digests.len != entry.size {
+        return Err(WriteError::FileChanged(entry.path.clone()));
+    }
+
+    entry.md5 = digests.md5;
+    entry.hmac = digests.hmac;
+    Ok(())
+}
+
+struct PreparedPackage<'a> {
+    source: &'a PackageSource,
+    files: Vec<FileEntry>,
+    strs: chunks::STRS,
+    file_infos: Vec<chunks::FileInfo>,
+    data_len: u64,
+}
+
+impl PreparedPackage<'_> {
+    /// The length of the `SPK0` payload, excluding its own header.
+    fn byte_len(&self) -> u64 {
+        let sidx_len = 8 + chunks::SIDX::BYTE_LEN;
+        let strs_len = 8 + self.strs.string_data.len() as u64;
+        let file_info_len = self
+            .file_infos
+            .iter()
+            .map(|file_info| match file_info {
+                chunks::FileInfo::FINF(_) => 8 + u64::from(chunks::FINF::BYTE_LEN),
+                chunks::FileInfo::FI64(_) => 8 + u64::from(chunks::FI64::BYTE_LEN),
+                chunks::FileInfo::FEND(_) => 8,
+            })
+            .sum::<u64>();
+        let sdat_len = chunks::ByteLen::new(self.data_len).header_size() + self.data_len;
+        sidx_len + strs_len + file_info_len + sdat_len
+    }
+}
+
+/// Uses `FINF` unless one of the entry's offsets or sizes requires `FI64`.
+fn file_info(file: &FileEntry, filename_offset: u64, data_offset: u64) -> chunks::FileInfo {
+    let filename = NullString::from(file.name.as_str());
+    if let (Ok(filename_offset), Ok(file_size), Ok(data_offset)) = (
+        u32::try_from(filename_offset),
+        u32::try_from(file.size),
+        u32::try_from(data_offset),
+    ) {
+        chunks::FileInfo::FINF(chunks::FINF {
+            byte_len: chunks::FINF::BYTE_LEN,
+            filename,
+            filename_offset,
+            file_size,
+            data_offset,
+            data_size: file_size,
+            mode: file.mode,
+            pad_before_hmac: [0; 3],
+            data_hmac: file.hmac,
+            data_md5: file.md5,
+            pad_after_md5: [0; 3],
+        })
+    } else {
+        chunks::FileInfo::FI64(chunks::FI64 {
+            byte_len: chunks::FI64::BYTE_LEN,
+            filename,
+            filename_offset,
+            file_size: file.size,
+            data_offset,
+            data_size: file.size,
+            mode: file.mode,
+            pad_before_hmac: [0; 3],
+            data_hmac: file.hmac,
+            data_md5: file.md5,
+            pad_after_md5: [0; 7],
+        })
+    }
+}
+
+fn prepare(source: &PackageSource) -> Result<PreparedPackage<'_>, WriteError> {
+    if source.name.len() > 0x20 {
+        return Err(WriteError::PackageNameTooLong(source.name.clone()));
+    }
+
+    let mut files = Vec::new();
+    collect_files(&source.directory, &source.directory, &mut files)?;
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut string_data = Vec::new();
+    let mut file_infos = Vec::new();
+    let mut data_offset = 0;
+    for file in &mut files {
+        hash_file(file)?;
+        file_infos.push(file_info(file, string_data.len() as u64, data_offset));
+        string_data.extend_from_slice(file.name.as_bytes());
+        string_data.push(0);
+        data_offset += file.size;
+    }
+    file_infos.push(chunks::FileInfo::FEND(chunks::FEND::new()));
+
+    let strs = chunks::STRS::new(string_data)
+        .map_err(|_| WriteError::StringTableTooLarge(source.name.clone()))?;
+
+    Ok(PreparedPackage {
+        source,
+        files,
+        strs,
+        file_infos,
+        data_len: data_offset,
+    })
+}
+
+fn write_package<W: Write>(
+    writer: &mut NoSeek<W>,
+    package: &PreparedPackage,
+) -> Result<(), WriteError> {
+    let source = package.source;
+    chunks::SPK0::new(package.byte_len()).write_le(writer)?;
+
+    let mut package_name = [0; 0x20];
+    package_name[..source.name.len()].copy_from_slice(source.name.as_bytes());
+    chunks::SIDX::new(package_name, source.version, source.type_).write_le(writer)?;
+
+    package.strs.write_le(writer)?;
+    package.file_infos.write_le(writer)?;
+
+    chunks::SDAT::new(package.data_len).write_le(writer)?;
+    for file in &package.files {
+        let mut reader = std::fs::File::open(&file.path)?.take(file.size);
+        if std::io::copy(&mut reader, writer)? != file.size {
+            return Err(WriteError::FileChanged(file.path.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Write a SPK file containing the given packages to `writer`.
+///
+/// Every regular file beneath each package's directory is included, named
+/// relative to that directory. File data is read twice: once to compute the
+/// MD5 and HMAC that precede the data, and once to copy it into `SDAT`.
+pub fn write<W: Write>(packages: &[PackageSource], writer: W) -> Result<(), WriteError> {
+    let chunk_count =
+        u32::try_from(packages.len()).map_err(|_| WriteError::TooManyPackages(packages.len()))?;
+
+    let packages = packages
+        .iter()
+        .map(prepare)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let byte_len = 4 + packages
+        .iter()
+        .map(|package| {
+            let byte_len = package.byte_len();
+            chunks::ByteLen::new(byte_len).header_size() + byte_len
+        })
+        .sum::<u64>();
+
+    let mut writer = NoSeek::new(writer);
+    chunks::SPKS::new(byte_len, chunk_count).write_le(&mut writer)?;
+
+    for package in &packages {
+        write_package(&mut writer, package)?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write a SPK file containing the given packages to the file at `path`.
+pub fn create(packages: &[PackageSource], path: &Path) -> Result<(), WriteError> {
+    let file = std::fs::File::create(path)?;
+    write(packages, std::io::BufWriter::new(file))
+}
+
+/// Wrap the .spk file at `spk_path` in a `SquashFS` file system and split it
+/// into pieces within `output_dir`, using the `<name>.spk.<count>.<index>`
+/// naming of Stern's split updates.
+///
+/// Each piece is at most `max_piece_size` bytes, or [`FAT32_MAX_FILE_SIZE`] if
+/// not specified. Returns the paths of the pieces in order.
+pub fn write_split_squashed(
+    spk_path: &Path,
+    output_dir: &Path,
+    max_piece_size: Option<u64>,
+) -> Result<Vec<PathBuf>, WriteError> {
+    let max_piece_size = max_piece_size.unwrap_or(FAT32_MAX_FILE_SIZE);
+    Ok(squashed::write_split_spk_file(
+        spk_path,
+        output_dir,
+        max_piece_size,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    use super::*;
+    use crate::spk;
+
+    fn write_file(path: &Path, contents: &[u8], mode: u32) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn written_packages_parse_and_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let spike = dir.path().join("spike");
+        write_file(
+            &spike.join("etc/fstab"),
+            b"proc /proc proc defaults 0 0\n",
+            0o644,
+        );
+        write_file(&spike.join("bin/run"), &vec![0xab; 100_000], 0o755);
+        let game = dir.path().join("game");
+        write_file(&game.join("game/sounds.bin"), b"", 0o600);
+
+        let packages = [
+            PackageSource {
+                name: "spike".to_string(),
+                version: (2, 7, 0),
+                type_: PackageType::Spike2,
+                directory: spike,
+            },
+            PackageSource {
+                name: "game".to_string(),
+                version: (1, 15, 3),
+                type_: PackageType::Game,
+                directory: game,
+            },
+        ];
+        let mut data = Vec::new();
+        write(&packages, &mut data).unwrap();
+
+        let file = spk::SPKFile::parse_bytes(data).unwrap();
+        let summary = file
+            .packages
+            .iter()
+            .map(|package| {
+                let files = package
+                    .files
+                    .iter()
+                    .map(|file| (file.name.as_str(), file.size, file.mode))
+                    .collect::<Vec<_>>();
+                (package.name.as_str(), package.version, package.type_, files)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (
+                    "spike",
+                    (2, 7, 0),
+                    PackageType::Spike2,
+                    vec![
+                        ("bin/run", 100_000, 0o100_755),
+                        ("etc/fstab", 29, 0o100_644),
+                    ]
+                ),
+                (
+                    "game",
+                    (1, 15, 3),
+                    PackageType::Game,
+                    vec![("game/sounds.bin", 0, 0o100_600)]
+                ),
+            ]
+        );
+
+        assert_eq!(
+            file.read(&file.packages[0].files[1]).unwrap(),
+            b"proc /proc proc defaults 0 0\n"
+        );
+        verify::verify_all(&file, &file.packages).unwrap();
+    }
+
+    #[test]
+    fn rejects_long_package_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let packages = [PackageSource {
+            name: "x".repeat(0x21),
+            version: (1, 0, 0),
+            type_: PackageType::Game,
+            directory: dir.path().to_path_buf(),
+        }];
+        assert!(matches!(
+            write(&packages, Vec::new()),
+            Err(WriteError::PackageNameTooLong(_))
+        ));
+    }
+}
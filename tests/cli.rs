@@ -0,0 +1,196 @@
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+use spike_spk::{
+    PackageType,
+    write::{self, PackageSource},
+};
+
+const CONTENTS: &[u8] = b"file contents";
+
+/// Write a SPK file containing a single file to `dir`, and return its path and data.
+fn spk_file(dir: &Path) -> (PathBuf, Vec<u8>) {
+    let package = dir.join("package");
+    std::fs::create_dir(&package).unwrap();
+    std::fs::write(package.join("a"), CONTENTS).unwrap();
+
+    let path = dir.join("test.spk");
+    write::create(
+        &[PackageSource {
+            name: "game".to_string(),
+            version: (1, 0, 0),
+            type_: PackageType::Game,
+            directory: package,
+        }],
+        &path,
+    )
+    .unwrap();
+    let data = std::fs::read(&path).unwrap();
+    (path, data)
+}
+
+fn spike_spk(args: &[&Path]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_spike-spk"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn verify(path: &Path) -> (Option<i32>, String) {
+    let output = spike_spk(&[Path::new("verify"), path]);
+    (
+        output.status.code(),
+        String::from_utf8(output.stdout).unwrap(),
+    )
+}
+
+fn position(data: &[u8], needle: &[u8]) -> usize {
+    data.windows(needle.len())
+        .position(|window| window == needle)
+        .unwrap()
+}
+
+#[test]
+fn verify_exits_with_success_when_every_file_passes() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, _) = spk_file(dir.path());
+
+    let (code, stdout) = verify(&path);
+    assert_eq!(code, Some(0));
+    assert!(stdout.ends_with("Checked 1 files: 1 passed, 0 failed\n"));
+}
+
+#[test]
+fn verify_exits_with_3_when_a_file_fails_verification() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, mut data) = spk_file(dir.path());
+    let offset = position(&data, CONTENTS);
+    data[offset] = b'F';
+    std::fs::write(&path, data).unwrap();
+
+    let (code, stdout) = verify(&path);
+    assert_eq!(code, Some(3));
+    assert!(
+        stdout.ends_with("Checked 1 files: 0 passed, 1 failed\nPackages with failures: game\n")
+    );
+}
+
+#[test]
+fn verify_exits_with_3_when_file_data_is_truncated() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, mut data) = spk_file(dir.path());
+    data.truncate(data.len() - 1);
+    std::fs::write(&path, data).unwrap();
+
+    let (code, stdout) = verify(&path);
+    assert_eq!(code, Some(3));
+    assert!(stdout.contains("File data ended after 12 of 13 bytes"));
+    assert!(stdout.contains("Checked 1 files: 0 passed, 1 failed\n"));
+}
+
+#[test]
+fn verify_exits_with_3_when_file_data_is_in_an_unsupported_encoding() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, mut data) = spk_file(dir.path());
+    // The file size follows the magic number, length and file name offset.
+    let file_size = position(&data, b"FINF") + 12;
+    data[file_size..file_size + 4].copy_from_slice(&100u32.to_le_bytes());
+    std::fs::write(&path, data).unwrap();
+
+    let (code, stdout) = verify(&path);
+    assert_eq!(code, Some(3));
+    assert!(stdout.contains("File a stores 13 bytes for 100 bytes of content"));
+    assert!(stdout.contains("Checked 1 files: 0 passed, 1 failed\n"));
+}
+
+#[test]
+fn verify_exits_with_4_when_the_file_cannot_be_parsed() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, mut data) = spk_file(dir.path());
+    data[0x17] = b'Y';
+    std::fs::write(&path, data).unwrap();
+
+    let output = spike_spk(&[Path::new("verify"), &path]);
+    assert_eq!(output.status.code(), Some(4));
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("Expected SIDX chunk in package 0 at offset 0x14, found \"SIDY\"")
+    );
+}
+
+#[test]
+fn verify_exits_with_5_when_the_file_cannot_be_read() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = spike_spk(&[Path::new("verify"), &dir.path().join("missing.spk")]);
+    assert_eq!(output.status.code(), Some(5));
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .starts_with("Error: Failed to read file: ")
+    );
+}
+
+#[test]
+fn verify_records_only_have_an_error_when_verification_fails() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, mut data) = spk_file(dir.path());
+    let records = |format: &str| {
+        let output = spike_spk(&[
+            Path::new("verify"),
+            Path::new("--format"),
+            Path::new(format),
+            &path,
+        ]);
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let json = records("ndjson");
+    assert!(json.contains(r#""status":"passed""#));
+    assert!(!json.contains("error"));
+    assert!(records("csv").ends_with(",passed,true,true,\n"));
+
+    data.truncate(data.len() - 1);
+    std::fs::write(&path, data).unwrap();
+    assert!(records("ndjson").contains(
+        r#""status":"failed","md5_matches":false,"hmac_matches":false,"error":"File data ended after 12 of 13 bytes""#
+    ));
+}
+
+#[test]
+fn inspect_prints_the_chunks_before_a_parse_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, mut data) = spk_file(dir.path());
+    let finf = position(&data, b"FINF");
+    data.truncate(finf + 20);
+    std::fs::write(&path, data).unwrap();
+
+    let output = spike_spk(&[Path::new("inspect"), &path]);
+    assert_eq!(output.status.code(), Some(4));
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let chunks = stdout
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("- "))
+        .map(str::trim_start)
+        .collect::<Vec<_>>();
+    assert_eq!(chunks.len(), 4);
+    assert!(chunks[0].starts_with("SPKS at 0x00000000: Old header (8 bytes), payload "));
+    assert!(chunks[1].starts_with("SPK0 at 0x0000000c: Old header (8 bytes), payload "));
+    assert_eq!(
+        chunks[2],
+        "SIDX at 0x00000014: Old header (8 bytes), payload 48 bytes"
+    );
+    assert_eq!(
+        chunks[3],
+        "STRS at 0x0000004c: Old header (8 bytes), payload 2 bytes"
+    );
+    assert!(stdout.contains("   - package_name: \"game\" "));
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with(&format!(
+        "Error: Failed to parse file: Failed to parse FINF chunk in package 0 at offset {finf:#x}: "
+    )));
+}